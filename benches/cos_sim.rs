@@ -0,0 +1,114 @@
+// Similarity scan over a 10,000-chunk store of 768-dimensional embeddings, the hot loop
+// of `RagStore::search`. Compare `cargo bench` with `cargo bench --features simd`
+// (add RUSTFLAGS="-C target-cpu=native" for the AVX2 path). With
+// `--features compressed-embeddings` the f16 scan is timed as well, and the memory saved
+// and the scores lost to f16 are printed before the timings.
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+
+#[path = "../src/rag/similarity.rs"]
+mod similarity;
+
+const CHUNKS: usize = 10_000;
+const DIMENSIONS: usize = 768;
+
+// xorshift, good enough for filler vectors and keeps the bench deterministic
+fn random_embedding(seed: &mut u64) -> Vec<f32> {
+    (0..DIMENSIONS)
+        .map(|_| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            (*seed % 2000) as f32 / 1000.0 - 1.0
+        })
+        .collect()
+}
+
+fn search_scan(c: &mut Criterion) {
+    let mut seed = 0x2545_f491_4f6c_dd1d;
+    let store: Vec<Vec<f32>> = (0..CHUNKS).map(|_| random_embedding(&mut seed)).collect();
+    let query = random_embedding(&mut seed);
+
+    c.bench_function("cos_sim 10k x 768", |b| {
+        b.iter(|| {
+            store
+                .iter()
+                .map(|embedding| similarity::cos_sim(black_box(&query), embedding))
+                .fold(f32::NEG_INFINITY, f32::max)
+        })
+    });
+}
+
+#[cfg(feature = "compressed-embeddings")]
+fn search_scan_f16(c: &mut Criterion) {
+    let to_f16 = |embedding: &[f32]| -> Vec<u16> {
+        embedding
+            .iter()
+            .map(|&x| half::f16::from_f32(x).to_bits())
+            .collect()
+    };
+    let mut seed = 0x2545_f491_4f6c_dd1d;
+    let store: Vec<Vec<f32>> = (0..CHUNKS).map(|_| random_embedding(&mut seed)).collect();
+    let query = random_embedding(&mut seed);
+    let store_f16: Vec<Vec<u16>> = store.iter().map(|embedding| to_f16(embedding)).collect();
+    let query_f16 = to_f16(&query);
+
+    let bytes = |sizes: &mut dyn Iterator<Item = usize>| sizes.sum::<usize>() as f64 / 1024.0;
+    let kib_f32 = bytes(&mut store.iter().map(|e| std::mem::size_of_val(e.as_slice())));
+    let kib_f16 = bytes(
+        &mut store_f16
+            .iter()
+            .map(|e| std::mem::size_of_val(e.as_slice())),
+    );
+    println!(
+        "memory: {:.0} KiB as f32, {:.0} KiB as f16 ({:.0}% less)",
+        kib_f32,
+        kib_f16,
+        100.0 * (1.0 - kib_f16 / kib_f32)
+    );
+
+    let exact: Vec<f32> = store
+        .iter()
+        .map(|embedding| similarity::cos_sim(&query, embedding))
+        .collect();
+    let compressed: Vec<f32> = store_f16
+        .iter()
+        .map(|embedding| similarity::cos_sim_f16(&query_f16, embedding))
+        .collect();
+    let errors: Vec<f32> = exact
+        .iter()
+        .zip(&compressed)
+        .map(|(a, b)| (a - b).abs())
+        .collect();
+    let top = |scores: &[f32]| {
+        let mut order: Vec<usize> = (0..scores.len()).collect();
+        order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+        order.truncate(10);
+        order
+    };
+    let (top_exact, top_compressed) = (top(&exact), top(&compressed));
+    println!(
+        "accuracy: max score error {:.2e}, mean {:.2e}, top-10 overlap {}/10",
+        errors.iter().copied().fold(0.0, f32::max),
+        errors.iter().sum::<f32>() / errors.len() as f32,
+        top_exact
+            .iter()
+            .filter(|i| top_compressed.contains(i))
+            .count()
+    );
+
+    c.bench_function("cos_sim_f16 10k x 768", |b| {
+        b.iter(|| {
+            store_f16
+                .iter()
+                .map(|embedding| similarity::cos_sim_f16(black_box(&query_f16), embedding))
+                .fold(f32::NEG_INFINITY, f32::max)
+        })
+    });
+}
+
+#[cfg(not(feature = "compressed-embeddings"))]
+criterion_group!(benches, search_scan);
+#[cfg(feature = "compressed-embeddings")]
+criterion_group!(benches, search_scan, search_scan_f16);
+criterion_main!(benches);
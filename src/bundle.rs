@@ -0,0 +1,128 @@
+use crate::config;
+use crate::rag;
+use anyhow::{Context, Result, anyhow};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+// A persona bundle is a .tar.gz with the persona file as `persona.toml` at the root and
+// its context files under `context/`. The bundled `context_paths` point at those files.
+const MANIFEST_NAME: &str = "persona.toml";
+const CONTEXT_DIR: &str = "context";
+
+// Writes `<name>` and every file its `context_paths` match to `output`, returns the number
+// of context files bundled.
+pub fn export_persona(name: &str, output: &Path) -> Result<usize> {
+    let persona = config::load_persona(name)?;
+    let persona_file = config::get_persona_file(name)?;
+    let mut manifest: toml::Table = toml::from_str(&fs::read_to_string(&persona_file)?)
+        .with_context(|| format!("Failed to parse TOML: {:?}", persona_file))?;
+
+    let file =
+        File::create(output).with_context(|| format!("Failed to create archive: {:?}", output))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    let mut bundled_paths = Vec::new();
+    for path_str in &persona.context_paths {
+        for path in rag::expand_context_path(path_str) {
+            if !path.is_file() {
+                continue;
+            }
+            let archive_path = Path::new(CONTEXT_DIR).join(relative_part(&path));
+            archive
+                .append_path_with_name(&path, &archive_path)
+                .with_context(|| format!("Failed to add {:?} to archive", path))?;
+            bundled_paths.push(toml::Value::String(
+                archive_path.to_string_lossy().into_owned(),
+            ));
+        }
+    }
+    let bundled = bundled_paths.len();
+    manifest.insert("context_paths".to_string(), bundled_paths.into());
+
+    let manifest = toml::to_string(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, MANIFEST_NAME, manifest.as_bytes())?;
+
+    archive.into_inner()?.finish()?;
+    Ok(bundled)
+}
+
+// Installs a bundle as `<name>.toml` with its context files in `<name>/`, both in the
+// personas directory. Returns the persona name.
+pub fn import_persona(archive_path: &Path) -> Result<String> {
+    let open = || -> Result<tar::Archive<GzDecoder<File>>> {
+        let file = File::open(archive_path)
+            .with_context(|| format!("Failed to open archive: {:?}", archive_path))?;
+        Ok(tar::Archive::new(GzDecoder::new(file)))
+    };
+
+    // the manifest decides where everything goes, so find it first
+    let mut manifest = None;
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() == Path::new(MANIFEST_NAME) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            manifest = Some(content);
+            break;
+        }
+    }
+    let manifest = manifest.ok_or_else(|| anyhow!("Archive has no {}", MANIFEST_NAME))?;
+    let mut manifest: toml::Table =
+        toml::from_str(&manifest).context("Failed to parse bundled persona")?;
+    let name = manifest
+        .get("name")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Bundled persona has no name"))?
+        .to_string();
+
+    config::ensure_config_dir_exists()?;
+    let persona_file = config::get_persona_file(&name)?;
+    if persona_file.exists() {
+        return Err(anyhow!(
+            "Persona '{}' already exists: {:?}",
+            name,
+            persona_file
+        ));
+    }
+    let data_dir = config::get_personas_dir()?.join(&name);
+    fs::create_dir_all(&data_dir)
+        .with_context(|| format!("Failed to create dir: {:?}", data_dir))?;
+
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.starts_with(CONTEXT_DIR) {
+            // unpack_in refuses paths that would escape `data_dir`
+            entry.unpack_in(&data_dir)?;
+        }
+    }
+
+    if let Some(toml::Value::Array(paths)) = manifest.get_mut("context_paths") {
+        for path in paths.iter_mut() {
+            if let Some(relative) = path.as_str() {
+                *path = toml::Value::String(data_dir.join(relative).to_string_lossy().into_owned());
+            }
+        }
+    }
+    fs::write(&persona_file, toml::to_string(&manifest)?)
+        .with_context(|| format!("Failed to write persona file: {:?}", persona_file))?;
+    Ok(name)
+}
+
+// `path` without its root and any `..`, so it can live inside the archive
+fn relative_part(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
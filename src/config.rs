@@ -1,7 +1,59 @@
+use crate::error::CliError;
+use crate::rag::{
+    self, ChunkStrategy, DEFAULT_EMBED_BATCH_SIZE, DEFAULT_EXCLUDE_PATTERNS, EmbedVendor,
+    SqliteContextConfig,
+};
+use crate::vendors::KNOWN_VENDORS;
 use anyhow::{Context, Result, anyhow};
 use serde::Deserialize;
+use serde::de::{self, Visitor};
+use std::collections::HashMap;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const EXAMPLE_PERSONAS: &[(&str, &str)] = &[
+    (
+        "default",
+        r#"name = "default"
+model = "gemini"
+system_prompt = "You are a helpful, concise assistant."
+"#,
+    ),
+    (
+        "coder",
+        r#"name = "coder"
+model = "gemini"
+system_prompt = """
+You are an experienced software engineer. Give precise, working code and
+explain the trade-offs briefly. Prefer idiomatic solutions.
+"""
+temperature = 0.2
+
+# files or glob patterns to use as RAG context, e.g. ["src/**/*.rs", "README.md"],
+# a leading ~ and $VARS are expanded
+context_paths = []
+
+# output of shell commands to index as well, run each time the context is built
+# context_commands = ["git log --oneline -50", "git status --short"]
+
+# rows of SQLite tables to index, one text column per row
+# [[context_sqlite]]
+# path = "~/notes/notes.db"
+# table = "notes"
+# text_column = "body"
+# id_column = "id"
+
+# chunk size and overlap in bytes per file extension (defaults: 2000 and 200),
+# code keeps more overlap so functions aren't cut off from their context
+# chunk_size_by_extension = { rs = 3000, md = 1500 }
+# chunk_overlap_by_extension = { rs = 400, md = 100 }
+
+# tools `aiterm ask-with-tools` may let the model call, none unless listed
+# allowed_tools = ["read_file"]
+"#,
+    ),
+];
 
 #[derive(Deserialize, Debug)]
 pub struct Persona {
@@ -9,39 +61,600 @@ pub struct Persona {
     pub model: String,
     pub system_prompt: String,
 
+    // vendor specific model id, e.g. "llama-3.1-70b-versatile" for groq
+    pub model_version: Option<String>,
+
+    // asked instead when the model is rate limited or unavailable, as <vendor>/<version>,
+    // e.g. "gemini/gemini-1.5-flash"
+    pub fallback_model: Option<String>,
+
+    // sampling params, left to the vendor default when unset
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+
+    // gemini only, harm category -> block threshold
+    pub safety_settings: Option<HashMap<String, String>>,
+
     #[serde(default)]
     pub context_paths: Vec<String>,
+
+    // glob patterns for files to skip inside context_paths directories
+    #[serde(default = "default_context_exclude_patterns")]
+    pub context_exclude_patterns: Vec<String>,
+
+    // index every file in the context directories, not just ones that look like text
+    #[serde(default)]
+    pub all_text_files: bool,
+
+    // web pages indexed alongside context_paths
+    #[serde(default)]
+    pub context_urls: Vec<String>,
+
+    // shell commands whose output is indexed too, e.g. "git log --oneline -50"
+    #[serde(default)]
+    pub context_commands: Vec<String>,
+
+    // SQLite tables whose rows are indexed too, e.g. a notes app's database
+    #[serde(default)]
+    pub context_sqlite: Vec<SqliteContextConfig>,
+
+    // knowledge bases `ask` searches as well, filled by `ask --save-to-knowledge-base`
+    #[serde(default)]
+    pub kb_context: Vec<String>,
+
+    // "gemini" or "together" (with TOGETHER_API_KEY), for the context's embeddings
+    #[serde(default)]
+    pub embed_vendor: EmbedVendor,
+
+    // embedding model for the context, "text-embedding-004" when unset (with together,
+    // "BAAI/bge-base-en-v1.5")
+    pub embed_model: Option<String>,
+
+    // diversity/relevance trade-off for RAG retrieval, 1.0 = plain similarity ranking
+    pub mmr_lambda: Option<f32>,
+
+    // search with a few model-written rephrasings of the prompt as well
+    #[serde(default)]
+    pub query_expansion: bool,
+
+    // optional second-stage reranker for RAG results, only "cohere" for now
+    pub reranker: Option<String>,
+
+    // "fixed" or "paragraphs", picked per file type when unset
+    pub chunk_strategy: Option<ChunkStrategy>,
+
+    // chunk size and overlap in bytes for files with these extensions, e.g.
+    // { rs = 3000 } and { rs = 400, md = 100 }
+    #[serde(default)]
+    pub chunk_size_by_extension: HashMap<String, usize>,
+    #[serde(default)]
+    pub chunk_overlap_by_extension: HashMap<String, usize>,
+
+    // context files larger than this are skipped, 1 MB when unset
+    pub max_context_file_size_bytes: Option<u64>,
+
+    // max texts per embedding API request
+    #[serde(default = "default_embed_batch_size")]
+    pub embed_batch_size: usize,
+
+    // chat re-indexes changed context files when this much time has passed
+    pub context_refresh_interval_secs: Option<u64>,
+
+    // for `ask --template <name>`, "{prompt}" is replaced with the prompt
+    #[serde(default)]
+    pub prompt_templates: HashMap<String, String>,
+
+    // start every `ask` question with today's date, like `ask --prepend-date`
+    #[serde(default)]
+    pub prepend_date: bool,
+
+    // let Gemini reason before answering, like `ask --thinking`
+    #[serde(default)]
+    pub thinking_enabled: bool,
+
+    // printed ahead of every `ask` response, e.g. "```json\n". None of the supported
+    // vendors can continue a prefilled assistant turn, so this is display only: the model
+    // never sees it, and it isn't part of the piped, cached or session-saved response.
+    // Ask for the matching format in system_prompt as well.
+    pub response_prefix: Option<String>,
+
+    // estimated token limit for a request, RAG snippets are dropped until it fits
+    pub max_prompt_tokens: Option<u32>,
+
+    // tools `ask-with-tools` may offer the model, e.g. ["read_file"]; none when empty
+    #[serde(default)]
+    pub allowed_tools: Vec<String>,
+}
+
+impl Persona {
+    // whether there is anything to build a RAG store from
+    pub fn has_context(&self) -> bool {
+        !self.context_paths.is_empty()
+            || !self.context_urls.is_empty()
+            || !self.context_commands.is_empty()
+            || !self.context_sqlite.is_empty()
+    }
+}
+
+fn default_embed_batch_size() -> usize {
+    DEFAULT_EMBED_BATCH_SIZE
+}
+
+fn default_context_exclude_patterns() -> Vec<String> {
+    DEFAULT_EXCLUDE_PATTERNS
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect()
+}
+
+// The keys a persona file may set, as serde passes `Persona`'s field names to the
+// deserializer. Nothing is deserialized; `FieldNames` records the names and bails out.
+fn persona_keys() -> &'static [&'static str] {
+    let mut keys: &'static [&'static str] = &[];
+    let _ = Persona::deserialize(FieldNames(&mut keys));
+    keys
+}
+
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("expected a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("only the field names are read"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+// Settings that apply to every persona, read from `config.toml` in the config directory.
+#[derive(Deserialize, Debug, Default)]
+pub struct GlobalConfig {
+    // proxy for all outgoing HTTP requests, e.g. "http://proxy.corp.example:3128"
+    pub proxy_url: Option<String>,
+    // used by `ask` and `chat` when no persona is given
+    pub default_persona: Option<String>,
+}
+
+// A missing config.toml is the same as an empty one.
+pub fn load_global_config() -> Result<GlobalConfig> {
+    let config_file = get_config_dir()?.join("config.toml");
+    if !config_file.exists() {
+        return Ok(GlobalConfig::default());
+    }
+    let file_content = fs::read_to_string(&config_file)
+        .with_context(|| format!("Failed to read config file: {:?}", config_file))?;
+    toml::from_str(&file_content)
+        .with_context(|| format!("Failed to parse TOML: {:?}", config_file))
+}
+
+// Sets `default_persona` in config.toml. The file is edited line by line, so comments
+// and the other settings stay as they were.
+pub fn set_default_persona(name: &str) -> Result<()> {
+    let persona_file = get_persona_file(name)?;
+    if !persona_file.exists() {
+        return Err(CliError::PersonaNotFound(persona_file).into());
+    }
+    let config_file = get_config_dir()?.join("config.toml");
+    let content = if config_file.exists() {
+        fs::read_to_string(&config_file)
+            .with_context(|| format!("Failed to read config file: {:?}", config_file))?
+    } else {
+        String::new()
+    };
+    fs::write(&config_file, with_default_persona(&content, name))
+        .with_context(|| format!("Failed to write config file: {:?}", config_file))
+}
+
+// `content` of config.toml with `default_persona` replaced, or added at the top.
+fn with_default_persona(content: &str, name: &str) -> String {
+    let setting = format!(
+        "default_persona = {}",
+        toml::Value::String(name.to_string())
+    );
+    let mut lines: Vec<&str> = content.lines().collect();
+    // settings below a table header would belong to that table
+    let existing = lines
+        .iter()
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix("default_persona")
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        });
+    match existing {
+        Some(idx) => lines[idx] = &setting,
+        None => lines.insert(0, &setting),
+    }
+    lines.join("\n") + "\n"
 }
 
-fn get_personas_dir() -> Result<PathBuf> {
+// The standard proxy variables, which apply when config.toml sets no proxy_url.
+pub fn proxy_from_env() -> Option<String> {
+    [
+        "HTTPS_PROXY",
+        "https_proxy",
+        "HTTP_PROXY",
+        "http_proxy",
+        "ALL_PROXY",
+        "all_proxy",
+    ]
+    .iter()
+    .find_map(|var| env::var(var).ok().filter(|value| !value.is_empty()))
+}
+
+pub fn get_config_dir() -> Result<PathBuf> {
     let config_dir =
         dirs::config_dir().ok_or_else(|| anyhow!("Could not find a valid config directory."))?;
-    Ok(config_dir.join("aiterm").join("personas"))
+    Ok(config_dir.join("aiterm"))
+}
+
+pub fn get_personas_dir() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("personas"))
+}
+
+pub fn get_persona_file(name: &str) -> Result<PathBuf> {
+    Ok(get_personas_dir()?.join(format!("{}.toml", name)))
 }
 
 pub fn load_persona(name: &str) -> Result<Persona> {
-    let personas_dir = get_personas_dir()?;
-    let persona_file = personas_dir.join(format!("{}.toml", name));
+    let persona_file = get_persona_file(name)?;
 
     if !persona_file.exists() {
-        return Err(anyhow!(
-            "Persona file not found: {:?}\nWill put a cool default later on",
-            persona_file
-        ));
+        return Err(CliError::PersonaNotFound(persona_file).into());
     }
+    read_persona(&persona_file)
+}
 
-    let file_content = fs::read_to_string(&persona_file)
+// A persona TOML anywhere on disk, not installed in the personas directory. Relative
+// context paths are taken from the file's directory rather than the current one.
+pub fn load_persona_file(persona_file: &Path) -> Result<Persona> {
+    let mut persona = read_persona(persona_file)?;
+    let dir = persona_file.parent().unwrap_or(Path::new(""));
+    for path in &mut persona.context_paths {
+        if Path::new(path.as_str()).is_relative() {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
+        }
+    }
+    for table in &mut persona.context_sqlite {
+        if Path::new(&table.path).is_relative() {
+            table.path = dir.join(&table.path).to_string_lossy().into_owned();
+        }
+    }
+    Ok(persona)
+}
+
+fn read_persona(persona_file: &Path) -> Result<Persona> {
+    let file_content = fs::read_to_string(persona_file)
         .with_context(|| format!("Failed to read persona file: {:?}", persona_file))?;
 
-    let persona: Persona = toml::from_str(&file_content)
+    let mut persona: Persona = toml::from_str(&file_content)
         .with_context(|| format!("Failed to parse TOML: {:?}", persona_file))?;
+    substitute_env_vars(&mut persona, persona_file)?;
 
     Ok(persona)
 }
 
+// Replaces `${VAR}` in the fields that commonly hold secrets or machine specific paths.
+// Paths get `expand_path` instead, which also handles `~` and `$VAR`.
+fn substitute_env_vars(persona: &mut Persona, persona_file: &Path) -> Result<()> {
+    persona.system_prompt = substitute_env(&persona.system_prompt, persona_file)?;
+    if let Some(model_version) = &persona.model_version {
+        persona.model_version = Some(substitute_env(model_version, persona_file)?);
+    }
+    for path in &mut persona.context_paths {
+        *path = expand_path(path).to_string_lossy().into_owned();
+    }
+    for table in &mut persona.context_sqlite {
+        table.path = expand_path(&table.path).to_string_lossy().into_owned();
+    }
+    Ok(())
+}
+
+// Expands a leading `~` to the home directory, and `$VAR` and `${VAR}` to the variable's
+// value. Unset variables are left in place, so the path still names them when it
+// doesn't exist.
+pub fn expand_path(s: &str) -> PathBuf {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    if let Some(home) = dirs::home_dir()
+        && (rest == "~" || rest.starts_with("~/"))
+    {
+        expanded.push_str(&home.to_string_lossy());
+        rest = &rest[1..];
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..start + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    PathBuf::from(expanded)
+}
+
+// `${` without a closing `}` is left as is.
+fn substitute_env(value: &str, persona_file: &Path) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let var = &rest[start + 2..start + 2 + len];
+        let var_value = env::var(var).map_err(|_| {
+            anyhow!(
+                "Environment variable '{}' referenced in {:?} is not set",
+                var,
+                persona_file
+            )
+        })?;
+        result.push_str(&rest[..start]);
+        result.push_str(&var_value);
+        rest = &rest[start + 3 + len..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Problems with a persona file that would otherwise only show up at runtime (or never).
+// An empty list means the persona is valid.
+pub fn validate_persona(name: &str) -> Result<Vec<String>> {
+    let persona_file = get_persona_file(name)?;
+    let file_content = fs::read_to_string(&persona_file)
+        .with_context(|| format!("Failed to read persona file: {:?}", persona_file))?;
+
+    let mut findings = Vec::new();
+    // typos like `temprature` would otherwise be ignored, a syntax error is reported below
+    if let Ok(table) = toml::from_str::<toml::Table>(&file_content) {
+        let known = persona_keys();
+        for key in table.keys().filter(|key| !known.contains(&key.as_str())) {
+            findings.push(format!("Unknown key '{}'", key));
+        }
+    }
+    let mut persona: Persona = match toml::from_str(&file_content) {
+        Ok(persona) => persona,
+        Err(e) => {
+            // nothing else can be checked without the values
+            findings.push(e.message().to_string());
+            return Ok(findings);
+        }
+    };
+    // paths with unresolved variables would only produce follow-up findings
+    let env_resolved = match substitute_env_vars(&mut persona, &persona_file) {
+        Ok(()) => true,
+        Err(e) => {
+            findings.push(e.to_string());
+            false
+        }
+    };
+
+    if !KNOWN_VENDORS.contains(&persona.model.as_str()) {
+        findings.push(format!(
+            "Unknown model '{}', expected one of: {}",
+            persona.model,
+            KNOWN_VENDORS.join(", ")
+        ));
+    }
+    for path in persona.context_paths.iter().filter(|_| env_resolved) {
+        if rag::expand_context_path(path).is_empty() {
+            findings.push(format!("Context path '{}' does not match any file", path));
+        }
+    }
+    for pattern in &persona.context_exclude_patterns {
+        if let Err(e) = glob::Pattern::new(pattern) {
+            findings.push(format!("Invalid exclude pattern '{}': {}", pattern, e));
+        }
+    }
+    for (ext, overlap) in &persona.chunk_overlap_by_extension {
+        let size = persona
+            .chunk_size_by_extension
+            .get(ext)
+            .copied()
+            .unwrap_or(rag::MAX_CHUNK_SIZE);
+        if *overlap >= size {
+            findings.push(format!(
+                "Chunk overlap {} for '{}' is not smaller than its chunk size {}",
+                overlap, ext, size
+            ));
+        }
+    }
+    if persona.system_prompt.trim().is_empty() {
+        findings.push("system_prompt is empty".to_string());
+    }
+    if let Some(temperature) = persona.temperature
+        && !(0.0..=2.0).contains(&temperature)
+    {
+        findings.push(format!("temperature {} is outside [0.0, 2.0]", temperature));
+    }
+    Ok(findings)
+}
+
+// Names of all `*.toml` files in the personas directory, sorted.
+pub fn list_persona_names() -> Result<Vec<String>> {
+    let personas_dir = get_personas_dir()?;
+    let entries = fs::read_dir(&personas_dir)
+        .with_context(|| format!("Failed to read personas dir: {:?}", personas_dir))?;
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| path.file_stem()?.to_str().map(String::from))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
 pub fn ensure_config_dir_exists() -> Result<()> {
     let personas_dir = get_personas_dir()?;
     fs::create_dir_all(&personas_dir)
         .with_context(|| format!("Failed to create config dir: {:?}", personas_dir))?;
     Ok(())
 }
+
+// Writes the example personas that don't exist yet and returns the names written.
+pub fn write_example_personas() -> Result<Vec<&'static str>> {
+    ensure_config_dir_exists()?;
+    let personas_dir = get_personas_dir()?;
+    let mut written = Vec::new();
+    for (name, content) in EXAMPLE_PERSONAS {
+        let persona_file = personas_dir.join(format!("{}.toml", name));
+        if persona_file.exists() {
+            continue;
+        }
+        fs::write(&persona_file, content)
+            .with_context(|| format!("Failed to write persona file: {:?}", persona_file))?;
+        written.push(*name);
+    }
+    Ok(written)
+}
+
+// Copies persona `source` to `dest` as is, apart from the `name` line. Returns the new
+// persona file.
+pub fn clone_persona(source: &str, dest: &str, force: bool) -> Result<PathBuf> {
+    if dest.is_empty() || dest.contains(['/', '\\']) {
+        return Err(CliError::InvalidArgument(format!("Invalid persona name '{}'", dest)).into());
+    }
+    let source_file = get_persona_file(source)?;
+    if !source_file.exists() {
+        return Err(CliError::PersonaNotFound(source_file).into());
+    }
+    let dest_file = get_persona_file(dest)?;
+    if dest_file.exists() && !force {
+        return Err(anyhow!(
+            "Persona '{}' already exists: {:?} (use --force to overwrite)",
+            dest,
+            dest_file
+        ));
+    }
+
+    let content = fs::read_to_string(&source_file)
+        .with_context(|| format!("Failed to read persona file: {:?}", source_file))?;
+    let name_line = format!("name = {}", toml::Value::String(dest.to_string()));
+    let mut lines: Vec<&str> = content.lines().collect();
+    // only a top-level `name` is the persona's, stop at the first table header
+    let name_idx = lines
+        .iter()
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix("name")
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        });
+    match name_idx {
+        Some(idx) => lines[idx] = &name_line,
+        None => lines.insert(0, &name_line),
+    }
+    fs::write(&dest_file, lines.join("\n") + "\n")
+        .with_context(|| format!("Failed to write persona file: {:?}", dest_file))?;
+    Ok(dest_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_path_handles_home_and_variables() {
+        let home = dirs::home_dir().unwrap();
+        let home_var = env::var("HOME").unwrap();
+        assert_eq!(expand_path("~/notes"), home.join("notes"));
+        assert_eq!(expand_path("~"), home);
+        assert_eq!(
+            expand_path("${HOME}/src"),
+            PathBuf::from(format!("{}/src", home_var))
+        );
+        assert_eq!(
+            expand_path("$HOME-old/*.md"),
+            PathBuf::from(format!("{}-old/*.md", home_var))
+        );
+        assert_eq!(
+            expand_path("$AITERM_UNSET_TEST_VAR/src"),
+            PathBuf::from("$AITERM_UNSET_TEST_VAR/src")
+        );
+        assert_eq!(expand_path("a~/b$"), PathBuf::from("a~/b$"));
+    }
+
+    #[test]
+    fn unset_variables_in_context_paths_are_left_in_place() {
+        let mut persona: Persona = toml::from_str(
+            "name = \"p\"\nmodel = \"gemini\"\nsystem_prompt = \"\"\n\
+             context_paths = [\"$AITERM_UNSET_TEST_VAR/a\", \"${AITERM_UNSET_TEST_VAR}/b\"]\n",
+        )
+        .unwrap();
+        substitute_env_vars(&mut persona, Path::new("p.toml")).unwrap();
+        assert_eq!(
+            persona.context_paths,
+            ["$AITERM_UNSET_TEST_VAR/a", "${AITERM_UNSET_TEST_VAR}/b"]
+        );
+    }
+
+    #[test]
+    fn default_persona_is_replaced_or_added_above_tables() {
+        let existing = "# mine\ndefault_persona = \"old\"\nproxy_url = \"x\"\n";
+        assert_eq!(
+            with_default_persona(existing, "new"),
+            "# mine\ndefault_persona = \"new\"\nproxy_url = \"x\"\n"
+        );
+
+        // the one in [pricing] is a different setting
+        let in_table = "proxy_url = \"x\"\n[pricing]\ndefault_persona = 1\n";
+        assert_eq!(
+            with_default_persona(in_table, "new"),
+            "default_persona = \"new\"\nproxy_url = \"x\"\n[pricing]\ndefault_persona = 1\n"
+        );
+
+        // no config.toml yet
+        assert_eq!(
+            with_default_persona("", "new"),
+            "default_persona = \"new\"\n"
+        );
+    }
+
+    #[test]
+    fn persona_keys_are_the_persona_fields() {
+        let keys = persona_keys();
+        assert!(keys.contains(&"temperature"));
+        assert!(keys.contains(&"allowed_tools"));
+        assert!(!keys.contains(&"temprature"));
+    }
+
+    #[test]
+    fn default_persona_must_exist() {
+        let e = set_default_persona("aiterm-test-no-such-persona").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(CliError::PersonaNotFound(_))
+        ));
+    }
+}
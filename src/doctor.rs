@@ -0,0 +1,127 @@
+use crate::config;
+use crate::rag;
+use crate::vendors::gemini::{self, Gemini};
+use std::env;
+
+#[derive(Default)]
+struct Report {
+    total: usize,
+    failed: usize,
+}
+
+impl Report {
+    // `problem` is None on success, otherwise the suggested fix
+    fn check(&mut self, name: &str, problem: Option<String>) {
+        self.total += 1;
+        match problem {
+            None => println!("\x1b[32m✓\x1b[0m {}", name),
+            Some(fix) => {
+                self.failed += 1;
+                println!("\x1b[31m✗\x1b[0m {}\n    → {}", name, fix);
+            }
+        }
+    }
+}
+
+// Runs every check, printing as it goes. Returns true if all of them passed.
+pub async fn run() -> bool {
+    let mut report = Report::default();
+
+    let personas_dir = config::get_personas_dir();
+    let dir_exists = personas_dir.as_ref().is_ok_and(|dir| dir.is_dir());
+    report.check(
+        "Config directory exists",
+        match &personas_dir {
+            Ok(_) if dir_exists => None,
+            Ok(dir) => Some(format!("Run `aiterm init` to create {}", dir.display())),
+            Err(e) => Some(format!("{}", e)),
+        },
+    );
+
+    let names = if dir_exists {
+        config::list_persona_names().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let mut broken = Vec::new();
+    let mut parsed = 0;
+    for name in &names {
+        match config::load_persona(name) {
+            Ok(_) => parsed += 1,
+            Err(e) => broken.push(format!("{}: {:#}", name, e)),
+        }
+    }
+    report.check(
+        "At least one persona file parses",
+        if parsed > 0 {
+            None
+        } else if names.is_empty() {
+            Some("No personas found, run `aiterm init` for examples".to_string())
+        } else {
+            Some(format!(
+                "Fix the persona files:\n      {}",
+                broken.join("\n      ")
+            ))
+        },
+    );
+    if parsed > 0 && !broken.is_empty() {
+        println!("    (some personas failed to parse: {})", broken.join("; "));
+    }
+
+    let api_key = env::var("GEMINI_API_KEY")
+        .ok()
+        .filter(|key| !key.is_empty());
+    report.check(
+        "GEMINI_API_KEY is set",
+        match api_key {
+            Some(_) => None,
+            None => Some("export GEMINI_API_KEY=<your key from Google AI Studio>".to_string()),
+        },
+    );
+
+    // informational only, both direct and proxied connections are fine
+    match config::load_global_config() {
+        Ok(config) => match config.proxy_url {
+            Some(proxy_url) => println!("  Proxy: {} (from config.toml)", proxy_url),
+            None => match config::proxy_from_env() {
+                Some(proxy_url) => println!("  Proxy: {} (from environment)", proxy_url),
+                None => println!("  Proxy: none detected"),
+            },
+        },
+        Err(e) => println!("  Proxy: unknown, {:#}", e),
+    }
+
+    let skipped = || Some("Skipped, GEMINI_API_KEY is not set".to_string());
+
+    report.check(
+        "Gemini API is reachable",
+        match &api_key {
+            Some(key) => match Gemini::new(key.clone(), gemini::DEFAULT_MODEL.to_string())
+                .count_tokens("ping")
+                .await
+            {
+                Ok(_) => None,
+                Err(e) => Some(format!("Check your key and network: {}", e)),
+            },
+            None => skipped(),
+        },
+    );
+
+    report.check(
+        "Embedding API is reachable",
+        match &api_key {
+            Some(key) => match rag::embed(key, &["ping".to_string()]).await {
+                Ok(_) => None,
+                Err(e) => Some(format!("Check your key and network: {:#}", e)),
+            },
+            None => skipped(),
+        },
+    );
+
+    if report.failed == 0 {
+        println!("\nAll checks passed.");
+    } else {
+        println!("\n{} of {} checks failed.", report.failed, report.total);
+    }
+    report.failed == 0
+}
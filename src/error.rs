@@ -0,0 +1,109 @@
+use crate::rag::EmbeddingApiError;
+use crate::vendors::VendorError;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+
+// Failures scripts may want to tell apart, each with its own exit code. Anything else
+// exits with 1.
+#[derive(Debug)]
+pub enum CliError {
+    PersonaNotFound(PathBuf),
+    // name of the environment variable
+    ApiKeyMissing(&'static str),
+    // 429, worth retrying later
+    ApiRateLimited,
+    // 401/403
+    ApiAuthFailed,
+    RagIndexFailed,
+    InvalidArgument(String),
+    // any other error reported by a vendor API
+    ApiFailed,
+    // Ctrl-C while a reply was streaming, after what arrived was shown and saved
+    Interrupted,
+    // exit code of the `ask --pipe-to` command, passed on as aiterm's own
+    PipeFailed(i32),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::PersonaNotFound(_) => 2,
+            CliError::ApiKeyMissing(_) => 3,
+            CliError::ApiRateLimited => 4,
+            CliError::ApiAuthFailed => 5,
+            CliError::RagIndexFailed => 6,
+            CliError::InvalidArgument(_) => 7,
+            CliError::ApiFailed => 8,
+            // 128 + SIGINT, what a shell reports for a process killed by Ctrl-C
+            CliError::Interrupted => 130,
+            CliError::PipeFailed(code) => *code,
+        }
+    }
+
+    fn from_vendor(e: &VendorError) -> Self {
+        match e {
+            VendorError::ApiError { status, .. } => CliError::from_status(*status),
+            _ => CliError::ApiFailed,
+        }
+    }
+
+    fn from_status(status: u16) -> Self {
+        match status {
+            429 => CliError::ApiRateLimited,
+            401 | 403 => CliError::ApiAuthFailed,
+            _ => CliError::ApiFailed,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::PersonaNotFound(path) => write!(
+                f,
+                "Persona file not found: {:?}\nRun `aiterm init` to create example personas.",
+                path
+            ),
+            CliError::ApiKeyMissing(var) => write!(f, "{} environment variable not set.", var),
+            CliError::ApiRateLimited => write!(f, "Rate limited by the API"),
+            CliError::ApiAuthFailed => write!(f, "The API rejected the credentials"),
+            CliError::RagIndexFailed => write!(f, "Failed to build the RAG index"),
+            CliError::InvalidArgument(message) => write!(f, "{}", message),
+            CliError::ApiFailed => write!(f, "API request failed"),
+            CliError::Interrupted => write!(f, "Interrupted"),
+            CliError::PipeFailed(code) => write!(f, "--pipe-to command exited with {}", code),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+pub fn api_key(var: &'static str) -> Result<String, CliError> {
+    env::var(var).map_err(|_| CliError::ApiKeyMissing(var))
+}
+
+// A required setting that isn't a key, such as an endpoint URL.
+pub fn env_setting(var: &'static str) -> Result<String, CliError> {
+    env::var(var)
+        .map_err(|_| CliError::InvalidArgument(format!("{} environment variable not set.", var)))
+}
+
+// The exit code for an error returned from `run`. A vendor or embedding API error
+// anywhere in the chain is classified by its status; a rate limit or rejected key says
+// more than whatever step it happened in.
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    let vendor_error = e
+        .downcast_ref::<VendorError>()
+        .map(CliError::from_vendor)
+        .or_else(|| {
+            e.downcast_ref::<EmbeddingApiError>()
+                .map(|e| CliError::from_status(e.status.as_u16()))
+        });
+    if let Some(cli_error @ (CliError::ApiRateLimited | CliError::ApiAuthFailed)) = &vendor_error {
+        return cli_error.exit_code();
+    }
+    e.downcast_ref::<CliError>()
+        .or(vendor_error.as_ref())
+        .map_or(1, CliError::exit_code)
+}
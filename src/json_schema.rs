@@ -0,0 +1,105 @@
+use serde_json::Value;
+
+// Where `value` breaks `schema`, one message per problem. Covers what Gemini's
+// responseSchema understands (type, properties, required, items, enum, nullable), so
+// a response can be checked the same way whichever vendor produced it. Type names may
+// be in either case, "OBJECT" as in Gemini's docs or "object" as in JSON Schema.
+pub fn violations(value: &Value, schema: &Value) -> Vec<String> {
+    let mut found = Vec::new();
+    check(value, schema, "$", &mut found);
+    found
+}
+
+fn check(value: &Value, schema: &Value, path: &str, found: &mut Vec<String>) {
+    if value.is_null() && schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+        return;
+    }
+    if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+        let matches = match expected.to_lowercase().as_str() {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            // unknown to us, don't guess
+            _ => true,
+        };
+        if !matches {
+            found.push(format!("{}: expected {}, got {}", path, expected, value));
+            return;
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+        && !allowed.contains(value)
+    {
+        found.push(format!(
+            "{}: {} is not one of {}",
+            path,
+            value,
+            Value::from(allowed.clone())
+        ));
+    }
+
+    if let Some(object) = value.as_object() {
+        for name in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(name) {
+                found.push(format!("{}: missing required property '{}'", path, name));
+            }
+        }
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property_schema) in properties {
+                if let Some(property) = object.get(name) {
+                    check(
+                        property,
+                        property_schema,
+                        &format!("{}.{}", path, name),
+                        found,
+                    );
+                }
+            }
+        }
+    }
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            check(item, item_schema, &format!("{}[{}]", path, i), found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_schema_violations_have_paths() {
+        let schema = serde_json::json!({
+            "type": "OBJECT",
+            "properties": {
+                "name": { "type": "STRING" },
+                "tags": { "type": "ARRAY", "items": { "type": "string", "enum": ["a", "b"] } },
+                "note": { "type": "STRING", "nullable": true }
+            },
+            "required": ["name", "tags"]
+        });
+        let valid = serde_json::json!({ "name": "x", "tags": ["a"], "note": null });
+        assert!(violations(&valid, &schema).is_empty());
+
+        let invalid = serde_json::json!({ "tags": ["a", "c", 1] });
+        assert_eq!(
+            violations(&invalid, &schema),
+            [
+                "$: missing required property 'name'",
+                "$.tags[1]: \"c\" is not one of [\"a\",\"b\"]",
+                "$.tags[2]: expected string, got 1",
+            ]
+        );
+    }
+}
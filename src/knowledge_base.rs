@@ -0,0 +1,87 @@
+use crate::config;
+use crate::error::CliError;
+use crate::rag::{self, EmbedVendor, RagStore};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Named RAG stores that grow with saved `ask` exchanges (`--save-to-knowledge-base`),
+// searched by personas listing them in `kb_context`. Each one is an exported index in
+// its own directory.
+
+// The name is used as a directory, so it has to be one plain path component: no
+// separators, and not `.` or `..`.
+fn index_file(name: &str) -> Result<PathBuf> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !plain || name.contains(['/', '\\']) {
+        return Err(
+            CliError::InvalidArgument(format!("Invalid knowledge base name '{}'", name)).into(),
+        );
+    }
+    Ok(config::get_config_dir()?
+        .join("knowledge_bases")
+        .join(name)
+        .join("index.bin"))
+}
+
+// The knowledge base `name`, None if nothing was saved to it yet. Knowledge bases are
+// shared between personas, so they always use the default embedding model.
+pub async fn open(api_key: &str, name: &str) -> Result<Option<RagStore>> {
+    let path = index_file(name)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    RagStore::import(
+        api_key.to_string(),
+        &path,
+        EmbedVendor::Gemini,
+        rag::DEFAULT_EMBED_MODEL,
+    )
+    .await
+    .map(Some)
+    .with_context(|| format!("Failed to load knowledge base '{}'", name))
+}
+
+// Adds a prompt and its response to `name` as one document, creating the knowledge base
+// if needed.
+pub async fn save_exchange(
+    api_key: &str,
+    name: &str,
+    persona: &str,
+    prompt: &str,
+    response: &str,
+) -> Result<()> {
+    let mut store = match open(api_key, name).await? {
+        Some(store) => store,
+        None => RagStore::empty(api_key.to_string()),
+    };
+    let source = format!("ask:{}:{}", chrono::Local::now().to_rfc3339(), persona);
+    let document = format!("User question: {}\n\nAnswer: {}", prompt, response);
+    store.add_document(source, document).await?;
+
+    let path = index_file(name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create dir: {:?}", dir))?;
+    }
+    store.export(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_must_be_a_single_plain_component() {
+        for name in [
+            "", ".", "..", "../notes", "a/b", "a\\b", "notes/", "./notes",
+        ] {
+            assert!(index_file(name).is_err(), "{:?} was accepted", name);
+        }
+        let path = index_file("notes").unwrap();
+        assert!(path.ends_with("knowledge_bases/notes/index.bin"));
+    }
+}
@@ -1,17 +1,44 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
 use clap::{Args, Parser, Subcommand};
 use std::env;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
 use tokio_stream::StreamExt;
 
+mod bundle;
 mod config;
+mod doctor;
+mod error;
+mod json_schema;
+mod knowledge_base;
+mod pipe;
 mod rag;
+mod response_cache;
+mod session;
+mod transcript;
 mod vendors;
 
 use crate::config::Persona;
-use crate::rag::RagStore;
-use vendors::gemini::Gemini;
-use vendors::{LanguageModel, Message};
+use crate::error::CliError;
+use crate::rag::{EmbedVendor, EmbeddingCache, RagStore, SearchResult, SharedEmbeddingCache};
+use crate::transcript::{
+    Transcript, TranscriptFormat, TurnLog, TurnRecord, diff_turn_records, read_turn_records,
+};
+use vendors::azure_openai::AzureOpenAI;
+use vendors::cohere::Cohere;
+use vendors::gemini::{self, Gemini};
+use vendors::groq::Groq;
+use vendors::huggingface::HuggingFaceInference;
+use vendors::together::TogetherAI;
+use vendors::{
+    AskWithToolsResult, GenerationConfig, InlineImage, LanguageModel, Message, ResponseMetadata,
+    ToolCall, ToolDefinition, VendorError, count_tokens,
+};
 
 // CLI
 #[derive(Parser, Debug)]
@@ -19,22 +46,152 @@ use vendors::{LanguageModel, Message};
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Print extra diagnostics to stderr.
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    /// Don't show progress bars.
+    #[arg(long, global = true)]
+    quiet: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Ask(AskArgs),
-    Converse(ConverseArgs),
+    Ask(Box<AskArgs>),
+    /// Ask with tools the model may call, currently a built-in read_file.
+    AskWithTools(AskWithToolsArgs),
+    Converse(Box<ConverseArgs>),
+    Chat(ChatArgs),
+    Benchmark(BenchmarkArgs),
+    /// Show how every chunk of a persona's context ranks for a query.
+    RagExplain {
+        #[arg(short, long)]
+        persona: String,
+
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
+
+        /// How many chunks a search would return
+        #[arg(long, default_value = "3")]
+        top_k: usize,
+    },
+    /// Group a persona's context into topics and describe each one.
+    RagClusters {
+        #[arg(short, long)]
+        persona: String,
+
+        #[arg(long, default_value = "5")]
+        num_clusters: usize,
+    },
+    /// Show the size of a persona's RAG index: chunks, sources and embedding memory.
+    RagStats {
+        #[arg(short, long)]
+        persona: String,
+
+        /// Print the stats as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the embedding vector of a text.
+    Embed {
+        text: String,
+
+        #[arg(long, value_enum, default_value_t = EmbedFormat::Json)]
+        format: EmbedFormat,
+    },
+    /// Print the cosine similarity of two texts' embeddings.
+    EmbedSimilarity {
+        text1: String,
+        text2: String,
+    },
+    /// List the models a vendor offers.
+    Models {
+        // vendor name as used in personas, e.g. "gemini"
+        vendor: String,
+    },
+    /// Show how the responses of two JSONL `converse` transcripts differ.
+    DiffTranscripts {
+        file1: PathBuf,
+        file2: PathBuf,
+    },
+    /// Manage persona files.
+    Persona {
+        #[command(subcommand)]
+        command: PersonaCommand,
+    },
+    /// Bundle a persona and its context files into a .tar.gz archive.
+    ExportPersona {
+        name: String,
+        output: PathBuf,
+    },
+    /// Install a persona bundle created by export-persona.
+    ImportPersona {
+        archive: PathBuf,
+    },
+    /// Delete all responses saved by `ask --cache`.
+    CacheClear,
+    /// Create the config directory with example personas.
+    Init,
+    /// Check configuration and API connectivity.
+    Doctor,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum EmbedFormat {
+    // a JSON array
+    Json,
+    // a NumPy .npy file of float32, e.g. `aiterm embed "text" --format npy > vec.npy`
+    Npy,
+}
+
+#[derive(Subcommand, Debug)]
+enum PersonaCommand {
+    /// Check a persona file for mistakes and report each one.
+    Validate { name: String },
+    /// Copy a persona under a new name.
+    Clone {
+        source: String,
+        dest: String,
+
+        /// Overwrite `dest` if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Use a persona when `ask` or `chat` is given none.
+    SetDefault { name: String },
 }
 
 #[derive(Args, Debug)]
 struct AskArgs {
+    /// Persona to ask, defaults to the one set with `persona set-default`
     #[arg(short, long)]
-    persona: String,
+    persona: Option<String>,
 
-    #[arg(required = true, num_args = 1..)]
+    /// Ask with a persona TOML that isn't installed, relative context paths in it are
+    /// taken from its directory
+    #[arg(long, conflicts_with_all = ["persona", "compare", "chain"])]
+    persona_from_file: Option<PathBuf>,
+
+    #[arg(required_unless_present = "watch_file", num_args = 1..)]
     prompt: Vec<String>,
 
+    /// Ask with the contents of this file, then again every time it is saved (Ctrl-C to
+    /// stop). With --template the contents fill in the template's {prompt}
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "prompt",
+            "compare",
+            "chain",
+            "benchmark_n",
+            "rewrite_prompt",
+            "interactive_confirm",
+            "save_to_knowledge_base",
+        ]
+    )]
+    watch_file: Option<PathBuf>,
+
     // stream response
     #[arg(long)]
     stream: bool,
@@ -42,6 +199,214 @@ struct AskArgs {
     // num of context chunks to retrieve for RAG
     #[arg(long, default_value = "3")]
     rag_chunks: usize,
+
+    /// Leave out retrieved chunks less similar to the prompt than this (-1.0 to 1.0)
+    #[arg(long)]
+    min_similarity: Option<f32>,
+
+    /// List where the retrieved context came from after the response
+    #[arg(long)]
+    show_sources: bool,
+
+    // keep the RAG index in sync with changes to the context files
+    #[arg(long)]
+    watch: bool,
+
+    /// Skip RAG context retrieval for this invocation
+    #[arg(long)]
+    no_rag: bool,
+
+    /// JSON file with the message history; it is sent along and the new exchange appended.
+    #[arg(long, alias = "session")]
+    session_file: Option<PathBuf>,
+
+    /// Keep only this many of the latest session messages, in the request and the file
+    #[arg(long, requires = "session_file")]
+    max_session_messages: Option<usize>,
+
+    /// Extra file, directory, glob or URL to use as context for this question (repeatable)
+    #[arg(long = "context")]
+    context: Vec<String>,
+
+    /// Don't require GEMINI_API_KEY; --context files are then included as raw text
+    #[arg(long)]
+    no_api_key_check: bool,
+
+    /// Add the prompt and response to this knowledge base, see the persona's kb_context
+    #[arg(long, conflicts_with = "no_api_key_check")]
+    save_to_knowledge_base: Option<String>,
+
+    /// Use this system prompt instead of the persona's
+    #[arg(long, conflicts_with = "system_prompt_append")]
+    system_prompt_override: Option<String>,
+
+    /// Add this to the end of the persona's system prompt
+    #[arg(long)]
+    system_prompt_append: Option<String>,
+
+    /// Reuse the saved response for an identical request instead of calling the API
+    #[arg(long)]
+    cache: bool,
+
+    /// Max age in seconds of a cached response for --cache
+    #[arg(long, default_value = "3600")]
+    cache_ttl: u64,
+
+    /// Show how the prompt is assembled and confirm before sending it
+    #[arg(long)]
+    explain: bool,
+
+    /// Send without asking for confirmation after --explain
+    #[arg(long, requires = "explain")]
+    yes: bool,
+
+    /// Open the assembled prompt in $EDITOR and send it as saved (empty to cancel)
+    #[arg(long, conflicts_with = "cache")]
+    interactive_confirm: bool,
+
+    /// Load the persona's RAG index from this file instead of re-embedding everything,
+    /// and save it there afterwards
+    #[arg(long)]
+    rag_index_file: Option<PathBuf>,
+
+    /// Pipe the response into this shell command's stdin (chunk by chunk with --stream)
+    #[arg(long)]
+    pipe_to: Option<String>,
+
+    /// Fill in this prompt template from the persona's prompt_templates with the prompt
+    #[arg(long)]
+    template: Option<String>,
+
+    /// Generate this many candidate responses (at most 8)
+    #[arg(
+        long,
+        default_value = "1",
+        conflicts_with_all = ["cache", "session_file", "pipe_to", "compare"]
+    )]
+    num_responses: usize,
+
+    /// Ask with this model instead of the persona's, as <vendor>/<version>, e.g.
+    /// "gemini/gemini-1.5-pro"
+    #[arg(long, value_parser = parse_model_override, conflicts_with = "compare")]
+    model_override: Option<(String, String)>,
+
+    /// Ask again with this model, as <vendor>/<version>, when the first one is rate
+    /// limited (429) or unavailable (503). Overrides the persona's fallback_model
+    #[arg(long, value_parser = parse_model_override, conflicts_with = "compare")]
+    fallback_model: Option<(String, String)>,
+
+    /// Skip context files larger than this many bytes (overrides the persona's limit)
+    #[arg(long)]
+    max_file_size: Option<u64>,
+
+    /// Embed the context with this Gemini model, e.g. "embedding-001" (overrides the
+    /// persona's embed_model)
+    #[arg(long)]
+    embed_model: Option<String>,
+
+    /// Have the model rephrase the prompt for clarity first, then ask (and search) with that
+    #[arg(long, conflicts_with = "compare")]
+    rewrite_prompt: bool,
+
+    /// Persona whose model does the --rewrite-prompt step (default: the asked persona)
+    #[arg(long, requires = "rewrite_prompt")]
+    rewrite_persona: Option<String>,
+
+    /// Print an estimated API cost for the request to stderr
+    #[arg(long)]
+    show_cost: bool,
+
+    /// Send an image (.jpg, .png or .webp) along with the prompt, repeatable (Gemini only)
+    #[arg(long, conflicts_with_all = ["cache", "compare"])]
+    image: Vec<PathBuf>,
+
+    /// Start the question with today's date, for models that don't know it
+    #[arg(long)]
+    prepend_date: bool,
+
+    /// Let the model reason before it answers (Gemini only)
+    #[arg(long)]
+    thinking: bool,
+
+    /// Tokens the model may spend on reasoning, implies --thinking [default: 8192]
+    #[arg(long)]
+    thinking_budget: Option<u32>,
+
+    /// Print the model's thought summaries ahead of the response, implies --thinking
+    #[arg(long, conflicts_with_all = ["stream", "num_responses"])]
+    show_thinking: bool,
+
+    /// JSON Schema file the response must follow; Gemini is constrained to it, other
+    /// vendors' responses are only checked
+    #[arg(long, conflicts_with_all = ["json_schema_inline", "cache", "num_responses"])]
+    json_schema: Option<PathBuf>,
+
+    /// Like --json-schema, with the schema itself as the argument
+    #[arg(long, conflicts_with_all = ["cache", "num_responses"])]
+    json_schema_inline: Option<String>,
+
+    /// Rephrase the prompt and ask again when the model refuses to answer
+    #[arg(long, conflicts_with = "num_responses")]
+    retry_on_refusal: bool,
+
+    /// How many times --retry-on-refusal asks again
+    #[arg(long, default_value = "1", requires = "retry_on_refusal")]
+    max_refusal_retries: usize,
+
+    /// Print the finish reason and safety ratings after the response (Gemini only)
+    #[arg(long, conflicts_with_all = ["stream", "num_responses"])]
+    include_usage: bool,
+
+    /// Run the whole ask this many times and print latency stats, retrieval included
+    #[arg(
+        long,
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = [
+            "cache",
+            "session_file",
+            "pipe_to",
+            "explain",
+            "interactive_confirm",
+            "save_to_knowledge_base",
+        ]
+    )]
+    benchmark_n: u32,
+
+    /// Ask two personas at once and show their answers side by side
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["PERSONA1", "PERSONA2"],
+        conflicts_with_all = ["persona", "stream", "session_file", "watch", "cache", "show_cost"]
+    )]
+    compare: Vec<String>,
+
+    /// Ask these personas in turn, each one getting the previous one's response as its
+    /// prompt, e.g. "extract,summarize,present"
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "persona",
+            "compare",
+            "stream",
+            "session_file",
+            "watch",
+            "cache",
+            "show_cost",
+        ]
+    )]
+    chain: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct AskWithToolsArgs {
+    #[arg(short, long)]
+    persona: String,
+
+    #[arg(required = true, num_args = 1..)]
+    prompt: Vec<String>,
 }
 
 #[derive(Args, Debug)]
@@ -50,8 +415,8 @@ struct ConverseArgs {
     #[arg(short, long, required = true, num_args = 2..)]
     persona: Vec<String>,
 
-    // initial prompt, by user
-    #[arg(required = true, num_args = 1.., last = true)]
+    // initial prompt, by user. Optional when continuing a --seed-file conversation
+    #[arg(required_unless_present = "seed_file", num_args = 1.., last = true)]
     prompt: Vec<String>,
 
     // num of turns the conversation should last. Each agent speaking once is a turn.
@@ -61,8 +426,115 @@ struct ConverseArgs {
     /// Num of context chunks to retrieve for RAG for each turn.
     #[arg(long, default_value = "2")]
     rag_chunks: usize,
+
+    /// Skip a turn if the agent takes longer than this many seconds to respond.
+    #[arg(long)]
+    turns_timeout: Option<u64>,
+
+    /// Persona that silently follows the conversation and summarizes it at the end.
+    #[arg(long)]
+    observer: Option<String>,
+
+    /// Skip RAG context retrieval for this invocation
+    #[arg(long)]
+    no_rag: bool,
+
+    /// Save the finished conversation as Markdown (or JSON for a .json path).
+    #[arg(long)]
+    save_transcript: Option<PathBuf>,
+
+    /// Let each agent pick the next speaker with a final "@Name" line instead of round-robin.
+    #[arg(long)]
+    directed: bool,
+
+    /// Drop the oldest turns from the history once it exceeds this many (estimated) tokens.
+    #[arg(long)]
+    max_history_tokens: Option<usize>,
+
+    /// Once over --max-history-tokens, replace the oldest half of the history with a summary
+    /// instead of dropping it.
+    #[arg(long, requires = "max_history_tokens")]
+    summarize_history: bool,
+
+    /// Persona that writes the --summarize-history summaries (default: the first agent).
+    #[arg(long, requires = "summarize_history")]
+    summary_model: Option<String>,
+
+    /// Distill the conversation into the system prompt of a new persona with this name.
+    #[arg(long)]
+    write_persona: Option<String>,
+
+    /// How turns are reported while the conversation runs.
+    #[arg(long, value_enum, default_value_t = TranscriptFormat::Text)]
+    transcript_format: TranscriptFormat,
+
+    /// Write the --transcript-format output here instead of stdout.
+    #[arg(long)]
+    transcript_file: Option<PathBuf>,
+
+    /// Use this text as an agent's response at a turn instead of asking the model,
+    /// as "<turn>:<agent>:<text>" (repeatable).
+    #[arg(long, value_parser = parse_injected_turn)]
+    inject_turn: Vec<InjectedTurn>,
+
+    /// Continue the conversation in this JSONL transcript (see --transcript-format jsonl).
+    #[arg(long)]
+    seed_file: Option<PathBuf>,
+
+    /// How often each agent speaks instead of round-robin, e.g. "critic=3,author=1" for
+    /// three critic turns per author turn. Every agent needs a weight.
+    #[arg(
+        long,
+        value_parser = parse_agent_weight,
+        value_delimiter = ',',
+        conflicts_with = "directed"
+    )]
+    agent_weights: Vec<(String, usize)>,
+
+    /// Don't color agent names and responses (never colored when stdout isn't a terminal).
+    #[arg(long)]
+    no_color: bool,
+}
+
+#[derive(Args, Debug)]
+struct ChatArgs {
+    // persona to chat with, defaults to the one set with `persona set-default`
+    persona: Option<String>,
+
+    // num of context chunks to retrieve for RAG on each message
+    #[arg(long, default_value = "3")]
+    rag_chunks: usize,
+}
+
+#[derive(Args, Debug)]
+struct BenchmarkArgs {
+    // persona whose model is benchmarked
+    persona: String,
+
+    // file with one prompt per line
+    prompt_file: PathBuf,
+
+    // how many times each prompt is sent
+    #[arg(long, default_value = "1")]
+    iterations: usize,
 }
 
+const EMBEDDING_CACHE_CAPACITY: usize = 10_000;
+// per file, for `ask --context` without an API key
+const RAW_CONTEXT_MAX_CHARS: usize = 4000;
+// Gemini's candidateCount limit, used for --num-responses with every vendor
+const MAX_NUM_RESPONSES: usize = 8;
+// model round trips allowed in ask-with-tools before giving up
+const MAX_TOOL_CALLS: usize = 5;
+const TOOL_OUTPUT_MAX_CHARS: usize = 20_000;
+// put ahead of the prompt when --retry-on-refusal asks again
+const REFUSAL_REPHRASE_INSTRUCTION: &str =
+    "Rewrite the following request in a more neutral, academic tone, then answer it:";
+// ANSI colors `converse` agents cycle through: blue, green, yellow, magenta
+const AGENT_COLORS: [&str; 4] = ["34", "32", "33", "35"];
+// quiet time after a change before `ask --watch-file` reads the file again
+const WATCH_FILE_DEBOUNCE: Duration = Duration::from_millis(200);
+
 // Agent-}
 struct Agent {
     persona: Persona,
@@ -72,181 +544,3191 @@ struct Agent {
 
 // main--------
 #[tokio::main]
-async fn main() -> Result<()> {
-    config::ensure_config_dir_exists()?;
+async fn main() {
     let cli = Cli::parse();
+    handle_ctrl_c();
+    // like returning the error from main, but with an exit code per kind of failure
+    if let Err(e) = run(cli).await {
+        // the stream already said it was cancelled, and the piped-to command speaks for
+        // itself
+        if !matches!(
+            e.downcast_ref(),
+            Some(CliError::Interrupted | CliError::PipeFailed(_))
+        ) {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(error::exit_code(&e));
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    if cli.quiet {
+        rag::hide_progress();
+    }
+    // doctor only reports on the config dir, it must not create it
+    if !matches!(cli.command, Commands::Doctor) {
+        config::ensure_config_dir_exists()?;
+    }
 
     match cli.command {
-        Commands::Ask(args) => run_ask(args).await,
-        Commands::Converse(args) => run_converse(args).await,
+        Commands::Ask(args) => run_ask(*args, cli.verbose).await,
+        Commands::AskWithTools(args) => run_ask_with_tools(args, cli.verbose).await,
+        Commands::Converse(args) => run_converse(*args, cli.verbose).await,
+        Commands::Chat(args) => run_chat(args, cli.verbose).await,
+        Commands::Benchmark(args) => run_benchmark(args, cli.verbose).await,
+        Commands::RagExplain {
+            persona,
+            query,
+            top_k,
+        } => run_rag_explain(&persona, &query.join(" "), top_k).await,
+        Commands::RagClusters {
+            persona,
+            num_clusters,
+        } => run_rag_clusters(&persona, num_clusters, cli.verbose).await,
+        Commands::RagStats { persona, json } => run_rag_stats(&persona, json).await,
+        Commands::Embed { text, format } => run_embed(&text, format).await,
+        Commands::EmbedSimilarity { text1, text2 } => {
+            let api_key = error::api_key("GEMINI_API_KEY")?;
+            let similarity = rag::text_similarity(&api_key, &text1, &text2).await?;
+            println!("{:.4}", similarity);
+            Ok(())
+        }
+        Commands::Models { vendor } => run_models(&vendor).await,
+        Commands::Persona { command } => match command {
+            PersonaCommand::Validate { name } => run_persona_validate(&name),
+            PersonaCommand::Clone {
+                source,
+                dest,
+                force,
+            } => {
+                let dest_file = config::clone_persona(&source, &dest, force)?;
+                println!("Cloned '{}' to '{}'.", source, dest);
+                println!("Edit {} to customize it.", dest_file.display());
+                Ok(())
+            }
+            PersonaCommand::SetDefault { name } => {
+                config::set_default_persona(&name)?;
+                println!("Default persona set to '{}'.", name);
+                Ok(())
+            }
+        },
+        Commands::ExportPersona { name, output } => {
+            let bundled = bundle::export_persona(&name, &output)?;
+            println!(
+                "Exported '{}' with {} context file(s) to {}",
+                name,
+                bundled,
+                output.display()
+            );
+            Ok(())
+        }
+        Commands::ImportPersona { archive } => {
+            let name = bundle::import_persona(&archive)?;
+            println!("Imported persona '{}'", name);
+            Ok(())
+        }
+        Commands::CacheClear => {
+            let removed = response_cache::clear()?;
+            println!("Removed {} cached response(s).", removed);
+            Ok(())
+        }
+        Commands::DiffTranscripts { file1, file2 } => {
+            let old = read_turn_records(&file1)?;
+            let new = read_turn_records(&file2)?;
+            let color = io::stdout().is_terminal();
+            print!("{}", diff_turn_records(&old, &new, color));
+            Ok(())
+        }
+        Commands::Init => run_init(),
+        Commands::Doctor => {
+            if !doctor::run().await {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
     }
 }
 
-async fn run_ask(args: AskArgs) -> Result<()> {
-    let persona = config::load_persona(&args.persona)?;
-    println!(
-        "Using persona: '{}' (Model: {})",
-        persona.name, persona.model
-    );
+// The vendor model id a persona's requests go to, e.g. for pricing lookups.
+fn model_version(persona: &Persona) -> &str {
+    match persona.model.as_str() {
+        "gemini" => persona
+            .model_version
+            .as_deref()
+            .unwrap_or(gemini::DEFAULT_MODEL),
+        "cohere" => persona.model_version.as_deref().unwrap_or("command-r-plus"),
+        _ => persona.model_version.as_deref().unwrap_or_default(),
+    }
+}
 
-    let api_key = env::var("GEMINI_API_KEY")
-        .map_err(|_| anyhow!("GEMINI_API_KEY environment variable not set."))?;
+fn build_model(persona: &Persona, api_key: &str, verbose: bool) -> Result<Box<dyn LanguageModel>> {
+    match persona.model.as_str() {
+        "gemini" => {
+            if api_key.is_empty() {
+                return Err(CliError::ApiKeyMissing("GEMINI_API_KEY").into());
+            }
+            let mut gemini = Gemini::new(api_key.to_string(), model_version(persona).to_string());
+            if let Some(settings) = &persona.safety_settings {
+                gemini = gemini.with_safety_settings(settings);
+            }
+            gemini.set_verbose(verbose);
+            Ok(Box::new(gemini))
+        }
+        "groq" => {
+            let groq_key = error::api_key("GROQ_API_KEY")?;
+            let model_version = persona.model_version.clone().ok_or_else(|| {
+                anyhow!(
+                    "Persona '{}' uses groq but has no model_version set.",
+                    persona.name
+                )
+            })?;
+            Ok(Box::new(Groq::new(groq_key, model_version)))
+        }
+        "cohere" => {
+            let cohere_key = error::api_key("COHERE_API_KEY")?;
+            Ok(Box::new(Cohere::new(
+                cohere_key,
+                model_version(persona).to_string(),
+            )))
+        }
+        "azure-openai" => Ok(Box::new(AzureOpenAI::new(
+            error::api_key("AZURE_OPENAI_API_KEY")?,
+            error::env_setting("AZURE_OPENAI_ENDPOINT")?,
+            error::env_setting("AZURE_OPENAI_DEPLOYMENT_NAME")?,
+        ))),
+        "huggingface" => {
+            let hf_key = error::api_key("HUGGINGFACE_API_KEY")?;
+            let model_id = persona.model_version.clone().ok_or_else(|| {
+                anyhow!(
+                    "Persona '{}' uses huggingface but has no model_version (model id) set.",
+                    persona.name
+                )
+            })?;
+            Ok(Box::new(HuggingFaceInference::new(hf_key, model_id)))
+        }
+        "together" => {
+            let together_key = error::api_key("TOGETHER_API_KEY")?;
+            let model_version = persona.model_version.clone().ok_or_else(|| {
+                anyhow!(
+                    "Persona '{}' uses together but has no model_version set.",
+                    persona.name
+                )
+            })?;
+            Ok(Box::new(TogetherAI::new(together_key, model_version)))
+        }
+        _ => Err(anyhow!(
+            "Unknown model '{}' in persona '{}'",
+            persona.model,
+            persona.name
+        )),
+    }
+}
 
-    let rag_store = if !persona.context_paths.is_empty() {
-        Some(RagStore::new(api_key.clone(), &persona.context_paths).await?)
-    } else {
-        None
+async fn build_rag_store(
+    persona: &Persona,
+    api_key: &str,
+    cache: Option<&SharedEmbeddingCache>,
+    index_file: Option<&Path>,
+) -> Result<Option<RagStore>> {
+    if !persona.has_context() {
+        return Ok(None);
+    }
+    // check the reranker before spending time on embeddings
+    let cohere_key = match persona.reranker.as_deref() {
+        Some("cohere") => Some(env::var("COHERE_API_KEY").map_err(|_| {
+            anyhow!("COHERE_API_KEY environment variable not set (needed by reranker).")
+        })?),
+        Some(other) => return Err(anyhow!("Unknown reranker '{}'", other)),
+        None => None,
     };
 
-    let model: Box<dyn LanguageModel> = match persona.model.as_str() {
-        "gemini" => Box::new(Gemini::new(api_key)),
-        _ => return Err(anyhow!("Unknown model '{}'", persona.model)),
-    };
+    let mut builder = RagStore::builder(embed_api_key(persona, api_key)?, &persona.context_paths)
+        .embed_vendor(persona.embed_vendor)
+        .urls(&persona.context_urls)
+        .commands(&persona.context_commands)
+        .sqlite(&persona.context_sqlite)
+        .embed_batch_size(persona.embed_batch_size)
+        .chunk_strategy(persona.chunk_strategy)
+        .chunk_sizes(
+            &persona.chunk_size_by_extension,
+            &persona.chunk_overlap_by_extension,
+        )
+        .max_file_size(
+            persona
+                .max_context_file_size_bytes
+                .unwrap_or(rag::DEFAULT_MAX_FILE_SIZE),
+        )
+        .exclude_patterns(&persona.context_exclude_patterns)
+        .all_text_files(persona.all_text_files)
+        .index_file(index_file.map(Path::to_path_buf));
+    if let Some(embed_model) = &persona.embed_model {
+        builder = builder.embed_model(embed_model);
+    }
+    if let Some(cache) = cache {
+        builder = builder.cache(Arc::clone(cache));
+    }
+    let mut store = builder.build().await.context(CliError::RagIndexFailed)?;
+    if let Some(lambda) = persona.mmr_lambda {
+        store = store.with_mmr_lambda(lambda);
+    }
+    if let Some(key) = cohere_key {
+        store = store.with_cohere_reranker(key);
+    }
+    Ok(Some(store))
+}
 
-    let prompt_str = args.prompt.join(" ");
-    println!("\nAsking: {}...", prompt_str);
+// The key for the persona's embed_vendor, `gemini_key` being GEMINI_API_KEY.
+fn embed_api_key(persona: &Persona, gemini_key: &str) -> Result<String> {
+    match persona.embed_vendor {
+        EmbedVendor::Gemini => Ok(gemini_key.to_string()),
+        EmbedVendor::Together => Ok(error::api_key("TOGETHER_API_KEY")?),
+    }
+}
 
-    let context_str = if let Some(store) = &rag_store {
-        println!("Searching for relevant context via API...");
-        let context_chunks = store.search(&prompt_str, args.rag_chunks).await?;
-        if !context_chunks.is_empty() {
-            println!("Found {} relevant context snippets.", context_chunks.len());
-            format!(
-                "Here is some relevant context from the local files:\n\n{}\n",
-                context_chunks.join("\n")
-            )
-        } else {
-            String::new()
+fn generation_config(persona: &Persona) -> GenerationConfig {
+    GenerationConfig {
+        temperature: persona.temperature,
+        top_p: persona.top_p,
+        max_tokens: persona.max_tokens,
+        response_schema: None,
+        images: Vec::new(),
+        thinking_budget: persona
+            .thinking_enabled
+            .then_some(gemini::DEFAULT_THINKING_BUDGET),
+        include_thoughts: false,
+    }
+}
+
+// Ctrl-C while a reply is streaming ends the stream rather than the process, so what
+// arrived so far can still be shown and saved. Anywhere else it exits as usual.
+static STREAMING: AtomicBool = AtomicBool::new(false);
+static STREAM_CANCELLED: AtomicBool = AtomicBool::new(false);
+static CANCEL_STREAM: Notify = Notify::const_new();
+
+fn handle_ctrl_c() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !STREAMING.load(Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            STREAM_CANCELLED.store(true, Ordering::SeqCst);
+            CANCEL_STREAM.notify_one();
         }
-    } else {
-        String::new()
-    };
+    });
+}
 
-    let final_content = format!(
-        "{}\n\n{}\n\nUser question: {}",
-        persona.system_prompt, context_str, prompt_str
-    );
+// whether the last streamed reply was cut short by Ctrl-C
+fn stream_cancelled() -> bool {
+    STREAM_CANCELLED.load(Ordering::SeqCst)
+}
 
-    let messages = vec![Message {
-        role: "user".to_string(),
-        content: final_content,
-    }];
+// streams the reply to stdout as it arrives and returns the full text, or as much of it
+// as arrived before Ctrl-C
+async fn print_stream(
+    model: &dyn LanguageModel,
+    messages: &[Message],
+    config: &GenerationConfig,
+) -> Result<String> {
+    print_stream_with(model, messages, config, |_| Ok(())).await
+}
 
-    if args.stream {
-        println!("\n--- Response Stream ---");
-        let mut response_stream = model.ask_stream(&messages).await.map_err(|e| anyhow!(e))?;
-        while let Some(chunk_result) = response_stream.next().await {
-            let chunk = chunk_result.map_err(|e| anyhow!(e))?;
+// print_stream that also hands every chunk to `on_chunk`
+async fn print_stream_with(
+    model: &dyn LanguageModel,
+    messages: &[Message],
+    config: &GenerationConfig,
+    mut on_chunk: impl FnMut(&str) -> Result<()>,
+) -> Result<String> {
+    let mut response_stream = model.ask_stream(messages, config).await?;
+    let mut full_response = String::new();
+    STREAM_CANCELLED.store(false, Ordering::SeqCst);
+    STREAMING.store(true, Ordering::SeqCst);
+    let result = async {
+        loop {
+            let chunk_result = tokio::select! {
+                // a permit left over from an earlier stream doesn't count
+                _ = CANCEL_STREAM.notified() => {
+                    if stream_cancelled() {
+                        println!("\n[Stream cancelled]");
+                        return Ok(());
+                    }
+                    continue;
+                }
+                chunk_result = response_stream.next() => chunk_result,
+            };
+            let Some(chunk_result) = chunk_result else {
+                return Ok(());
+            };
+            let chunk = chunk_result?;
             print!("{}", chunk);
             io::stdout().flush()?;
+            on_chunk(&chunk)?;
+            full_response.push_str(&chunk);
         }
-        println!();
-    } else {
-        let response = model.ask(&messages).await.map_err(|e| anyhow!(e))?;
-        println!("\n--- Response ---\n{}", response);
     }
-
-    Ok(())
+    .await;
+    STREAMING.store(false, Ordering::SeqCst);
+    result.map(|()| full_response)
 }
 
-async fn run_converse(args: ConverseArgs) -> Result<()> {
-    println!("Starting a conversation with: {}", args.persona.join(", "));
-    let api_key = env::var("GEMINI_API_KEY")
-        .map_err(|_| anyhow!("GEMINI_API_KEY environment variable not set."))?;
+async fn run_ask(mut args: AskArgs, verbose: bool) -> Result<()> {
+    if !args.compare.is_empty() {
+        return run_compare(&args, verbose).await;
+    }
+    if !args.chain.is_empty() {
+        return run_chain(&args, verbose).await;
+    }
+    if let Some(path) = &args.watch_file {
+        args.prompt = vec![read_watched_file(path)?];
+    }
+    let mut persona = match &args.persona_from_file {
+        Some(path) => {
+            println!("Using ad-hoc persona from: {}", path.display());
+            config::load_persona_file(path)?
+        }
+        None => config::load_persona(&persona_or_default(args.persona.as_deref())?)?,
+    };
+    println!(
+        "Using persona: '{}' (Model: {})",
+        persona.name, persona.model
+    );
+    if let Some(max_file_size) = args.max_file_size {
+        persona.max_context_file_size_bytes = Some(max_file_size);
+    }
+    if let Some(embed_model) = &args.embed_model {
+        persona.embed_model = Some(embed_model.clone());
+    }
+    apply_model_override(&args, &mut persona);
+    // after --model-override, which decides the model the image goes to
+    check_image_vendor(&args, &persona)?;
 
-    // load agents
-    let mut agents = Vec::new();
-    for p_name in &args.persona {
-        let persona = config::load_persona(p_name)?;
-        let model: Box<dyn LanguageModel> = match persona.model.as_str() {
-            "gemini" => Box::new(Gemini::new(api_key.clone())),
-            _ => {
-                return Err(anyhow!(
-                    "Unknown model '{}' in persona '{}'",
-                    persona.model,
-                    p_name
-                ));
+    let api_key = match env::var("GEMINI_API_KEY") {
+        Ok(key) => Some(key),
+        Err(_) if args.no_api_key_check => None,
+        Err(_) => return Err(CliError::ApiKeyMissing("GEMINI_API_KEY").into()),
+    };
+
+    let mut rag_stores = Vec::new();
+    let mut raw_context = Vec::new();
+    match &api_key {
+        Some(api_key) => {
+            if !args.no_rag
+                && let Some(store) =
+                    build_rag_store(&persona, api_key, None, args.rag_index_file.as_deref()).await?
+            {
+                rag_stores.push(if args.watch {
+                    store.watch()?.0
+                } else {
+                    Arc::new(RwLock::new(store))
+                });
+            }
+            for name in persona.kb_context.iter().filter(|_| !args.no_rag) {
+                match knowledge_base::open(api_key, name).await? {
+                    Some(store) => rag_stores.push(Arc::new(RwLock::new(store))),
+                    None => println!("Warning: Knowledge base '{}' is empty, skipping it.", name),
+                }
+            }
+            let (urls, paths): (Vec<String>, Vec<String>) = args
+                .context
+                .iter()
+                .cloned()
+                .partition(|c| c.starts_with("http://") || c.starts_with("https://"));
+            if !paths.is_empty() {
+                let store = RagStore::builder(embed_api_key(&persona, api_key)?, &paths)
+                    .embed_vendor(persona.embed_vendor)
+                    .embed_model(
+                        persona
+                            .embed_model
+                            .as_deref()
+                            .unwrap_or(persona.embed_vendor.default_model()),
+                    )
+                    .max_file_size(
+                        persona
+                            .max_context_file_size_bytes
+                            .unwrap_or(rag::DEFAULT_MAX_FILE_SIZE),
+                    )
+                    .build()
+                    .await?;
+                rag_stores.push(Arc::new(RwLock::new(store)));
             }
+            if !urls.is_empty() {
+                let store = RagStore::from_urls(api_key.clone(), &urls).await?;
+                rag_stores.push(Arc::new(RwLock::new(store)));
+            }
+        }
+        None => {
+            if !args.no_rag && (persona.has_context() || !persona.kb_context.is_empty()) {
+                println!("Warning: Skipping persona context, embeddings need GEMINI_API_KEY.");
+            }
+            raw_context = rag::raw_context(&args.context, RAW_CONTEXT_MAX_CHARS);
+        }
+    }
+
+    let mut model = build_model(&persona, api_key.as_deref().unwrap_or_default(), verbose)?;
+    if args.rewrite_prompt {
+        let prompt = args.prompt.join(" ");
+        let rewritten = match &args.rewrite_persona {
+            Some(name) => {
+                let rewriter = config::load_persona(name)?;
+                let rewriter_model =
+                    build_model(&rewriter, api_key.as_deref().unwrap_or_default(), verbose)?;
+                rewrite_prompt(
+                    rewriter_model.as_ref(),
+                    &generation_config(&rewriter),
+                    &prompt,
+                )
+                .await?
+            }
+            None => rewrite_prompt(model.as_ref(), &generation_config(&persona), &prompt).await?,
         };
-        let rag_store = if !persona.context_paths.is_empty() {
-            Some(RagStore::new(api_key.clone(), &persona.context_paths).await?)
-        } else {
-            None
+        println!("[Rewritten prompt: {}]", rewritten);
+        args.prompt = vec![rewritten];
+    }
+    let mut fallback = match (&args.fallback_model, &persona.fallback_model) {
+        (Some(fallback), _) => Some(fallback.clone()),
+        (None, Some(spec)) => Some(parse_model_override(spec).map_err(|e| {
+            CliError::InvalidArgument(format!("Invalid fallback_model '{}': {}", spec, e))
+        })?),
+        (None, None) => None,
+    };
+    let rag_stores: Vec<&RwLock<RagStore>> = rag_stores.iter().map(Arc::as_ref).collect();
+    let mut latencies = Vec::new();
+    let mut responses = Vec::new();
+    for _ in 0..args.benchmark_n {
+        let start = Instant::now();
+        let response = match ask(
+            &args,
+            verbose,
+            &persona,
+            model.as_ref(),
+            &rag_stores,
+            raw_context.clone(),
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                // only once, later runs keep using the fallback
+                let (Some(reason), Some((vendor, version))) = (fallback_reason(&e), &fallback)
+                else {
+                    return Err(e);
+                };
+                let primary = model_version(&persona).to_string();
+                persona.model = vendor.clone();
+                persona.model_version = Some(version.clone());
+                model = build_model(&persona, api_key.as_deref().unwrap_or_default(), verbose)?;
+                fallback = None;
+                let response = ask(
+                    &args,
+                    verbose,
+                    &persona,
+                    model.as_ref(),
+                    &rag_stores,
+                    raw_context.clone(),
+                )
+                .await?;
+                println!(
+                    "[Fell back to {} due to {} on {}]",
+                    model_version(&persona),
+                    reason,
+                    primary
+                );
+                response
+            }
         };
-        agents.push(Agent {
-            persona,
-            model,
-            rag_store,
-        });
+        responses.push(response);
+        latencies.push(start.elapsed());
+    }
+    let response = responses.swap_remove(0);
+    if args.benchmark_n > 1 {
+        print_ask_benchmark(&response, latencies);
+    }
+    // empty when the prompt wasn't sent
+    if let Some(kb) = &args.save_to_knowledge_base
+        && !response.is_empty()
+    {
+        // --save-to-knowledge-base conflicts with --no-api-key-check, but saving embeds
+        let api_key = api_key
+            .as_deref()
+            .ok_or(CliError::ApiKeyMissing("GEMINI_API_KEY"))?;
+        let prompt = ask_prompt(&args, &persona)?;
+        knowledge_base::save_exchange(api_key, kb, &persona.name, &prompt, &response).await?;
+        println!("Saved to knowledge base '{}'.", kb);
     }
 
-    // initialize converse
-    let initial_prompt = args.prompt.join(" ");
-    let mut conversation_history = format!(
-        "The user started the conversation with this prompt: \"{}\"",
-        initial_prompt
-    );
-
-    // go
-    for i in 0..args.turns {
-        let current_agent_index = i % agents.len();
-        let agent = &agents[current_agent_index];
-
+    if let Some(path) = args.watch_file.clone() {
+        let (_watcher, mut changes) = watch_file(&path)?;
         println!(
-            "\n--- Turn {}/{} | Speaking: {} ---",
-            i + 1,
-            args.turns,
-            agent.persona.name
+            "\n[Watching {} for changes, Ctrl-C to stop]",
+            path.display()
         );
-
-        // RAG search for the current turn based on the latest history
-        let context_str = if let Some(store) = &agent.rag_store {
-            let context_chunks = store.search(&conversation_history, args.rag_chunks).await?;
-            if !context_chunks.is_empty() {
-                format!("CONTEXT:\n{}\n", context_chunks.join("\n"))
-            } else {
-                String::new()
+        while changes.recv().await.is_some() {
+            // editors fire several events per save
+            tokio::time::sleep(WATCH_FILE_DEBOUNCE).await;
+            while changes.try_recv().is_ok() {}
+            let prompt = match read_watched_file(&path) {
+                Ok(prompt) => prompt,
+                // e.g. removed for a moment while the editor replaces it
+                Err(_) => continue,
+            };
+            // saved without changes
+            if args.prompt == [prompt.as_str()] {
+                continue;
             }
-        } else {
-            String::new()
-        };
+            args.prompt = vec![prompt];
+            println!("\n[File changed, re-asking...]");
+            if let Err(e) = ask(
+                &args,
+                verbose,
+                &persona,
+                model.as_ref(),
+                &rag_stores,
+                raw_context.clone(),
+            )
+            .await
+            {
+                if matches!(e.downcast_ref(), Some(CliError::Interrupted)) {
+                    return Err(e);
+                }
+                eprintln!("Error: {:#}", e);
+            }
+        }
+    }
+    Ok(())
+}
 
-        // abother prompt for this turn
-        let turn_prompt = format!(
-            "YOUR ROLE:\n{system_prompt}\n\n{context}\n\nCONVERSATION HISTORY:\n---\n{history}\n---\n\nINSTRUCTIONS: Your name is {name}. Based on your role and the history, provide your response. Do NOT include your name or role in the response itself. Just give your conversational reply.",
-            system_prompt = agent.persona.system_prompt,
-            context = context_str,
-            history = conversation_history,
-            name = agent.persona.name
-        );
+fn read_watched_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))
+}
 
-        let messages = vec![Message {
-            role: "user".to_string(),
-            content: turn_prompt,
-        }];
+// Sends a message whenever `path` is written or replaced. Its directory is watched
+// rather than the file, as editors often save by renaming a new file over the old one.
+fn watch_file(
+    path: &Path,
+) -> Result<(
+    notify::RecommendedWatcher,
+    tokio::sync::mpsc::UnboundedReceiver<()>,
+)> {
+    use notify::{EventKind, RecursiveMode, Watcher};
 
-        // agent's response
-        let mut response_stream = agent
-            .model
-            .ask_stream(&messages)
-            .await
-            .map_err(|e| anyhow!(e))?;
-        let mut full_response = String::new();
-        while let Some(chunk_result) = response_stream.next().await {
-            let chunk = chunk_result.map_err(|e| anyhow!(e))?;
-            print!("{}", chunk);
-            io::stdout().flush()?;
-            full_response.push_str(&chunk);
+    let file_name = path
+        .file_name()
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| anyhow!("Not a file: {:?}", path))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res
+            && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event
+                .paths
+                .iter()
+                .any(|p| p.file_name() == Some(file_name.as_os_str()))
+        {
+            let _ = tx.send(());
+        }
+    })
+    .context("Failed to create file watcher")?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+    Ok((watcher, rx))
+}
+
+fn apply_model_override(args: &AskArgs, persona: &mut Persona) {
+    if let Some((vendor, version)) = &args.model_override {
+        println!(
+            "[Model overridden from '{}' to '{}/{}']",
+            persona.model, vendor, version
+        );
+        persona.model = vendor.clone();
+        persona.model_version = Some(version.clone());
+    }
+}
+
+// Only Gemini requests carry images.
+fn check_image_vendor(args: &AskArgs, persona: &Persona) -> Result<()> {
+    if !args.image.is_empty() && persona.model != "gemini" {
+        return Err(CliError::InvalidArgument(format!(
+            "--image needs a model that accepts images, {} doesn't (use a gemini persona)",
+            persona.model
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// The part of `ask` past setup: retrieval, the request itself and the session update.
+// `raw_context` snippets are added to whatever the stores return.
+async fn ask(
+    args: &AskArgs,
+    verbose: bool,
+    persona: &Persona,
+    model: &dyn LanguageModel,
+    rag_stores: &[&RwLock<RagStore>],
+    raw_context: Vec<String>,
+) -> Result<String> {
+    let mut gen_config = generation_config(persona);
+    gen_config.response_schema = ask_json_schema(args)?;
+    gen_config.images = args
+        .image
+        .iter()
+        .map(|path| load_image(path))
+        .collect::<Result<_>>()?;
+    if gen_config.response_schema.is_some() && persona.model != "gemini" {
+        println!(
+            "Note: {} can't be constrained to a schema, the response will only be checked.",
+            persona.model
+        );
+    }
+    if args.thinking || args.thinking_budget.is_some() || args.show_thinking {
+        gen_config.thinking_budget = Some(
+            args.thinking_budget
+                .unwrap_or(gemini::DEFAULT_THINKING_BUDGET),
+        );
+    }
+    gen_config.include_thoughts = args.show_thinking;
+    if gen_config.thinking_budget.is_some() && persona.model != "gemini" {
+        println!(
+            "Note: {} has no thinking mode, asking without it.",
+            persona.model
+        );
+    }
+
+    let prompt_str = ask_prompt(args, persona)?;
+    println!("\nAsking: {}...", prompt_str);
+
+    let mut context_chunks = Vec::new();
+    // similarity of each chunk for --explain, None for raw context
+    let mut scores = Vec::new();
+    // for --show-sources, the retrieved chunks come first in context_chunks
+    let mut sources = Vec::new();
+    if !rag_stores.is_empty() {
+        println!("Searching for relevant context via API...");
+    }
+    for store in rag_stores {
+        let store = store.read().await;
+        let mut results = if persona.query_expansion {
+            store
+                .search_with_expansion(
+                    &prompt_str,
+                    args.rag_chunks,
+                    rag::DEFAULT_QUERY_EXPANSIONS,
+                    model,
+                )
+                .await?
+        } else {
+            store.search(&prompt_str, args.rag_chunks).await?
+        };
+        if let Some(min_similarity) = args.min_similarity {
+            results.retain(|result| result.similarity >= min_similarity);
+        }
+        for result in results {
+            context_chunks.push(context_snippet(&result));
+            scores.push(Some(result.similarity));
+            sources.push(result);
+        }
+    }
+    scores.extend(raw_context.iter().map(|_| None));
+    context_chunks.extend(raw_context);
+    if !context_chunks.is_empty() {
+        println!("Found {} relevant context snippets.", context_chunks.len());
+    }
+
+    let system_prompt = ask_system_prompt(args, persona);
+    // added after retrieval, the date would only skew the search
+    let question = if args.prepend_date || persona.prepend_date {
+        format!(
+            "Today's date is {}.\n\n{}",
+            chrono::Local::now().format("%Y-%m-%d"),
+            prompt_str
+        )
+    } else {
+        prompt_str.clone()
+    };
+    let build_content = |chunks: &[String]| ask_content(&system_prompt, chunks, &question);
+    let mut final_content = build_content(&context_chunks);
+
+    let mut history = match &args.session_file {
+        Some(path) => session::load(path)?,
+        None => Vec::new(),
+    };
+    if let Some(max) = args.max_session_messages {
+        session::keep_last(&mut history, max);
+    }
+
+    if let Some(max_prompt_tokens) = persona.max_prompt_tokens {
+        let history_tokens: usize = history.iter().map(|m| count_tokens(&m.content)).sum();
+        let mut dropped = 0;
+        while history_tokens + count_tokens(&final_content) > max_prompt_tokens as usize
+            && let Some(index) = least_relevant(&scores)
+        {
+            context_chunks.remove(index);
+            scores.remove(index);
+            // retrieved chunks come first, so they share their index with `sources`
+            if index < sources.len() {
+                sources.remove(index);
+            }
+            dropped += 1;
+            final_content = build_content(&context_chunks);
+        }
+        if dropped > 0 {
+            println!(
+                "Warning: Dropped {} context snippet(s) to stay within max_prompt_tokens ({}).",
+                dropped, max_prompt_tokens
+            );
+        }
+    }
+
+    if args.explain {
+        print_explanation(
+            persona,
+            &system_prompt,
+            &question,
+            &context_chunks,
+            &scores,
+            history.len(),
+        );
+        if !args.yes && !confirm("Send this prompt?")? {
+            println!("Not sent.");
+            return Ok(String::new());
+        }
+    }
+    if args.interactive_confirm {
+        let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+        final_content = edit_in_editor(&editor, &final_content)?;
+        if final_content.trim().is_empty() {
+            println!("Empty prompt, not sent.");
+            return Ok(String::new());
+        }
+    }
+    let cache_key = args.cache.then(|| {
+        response_cache::key(
+            &persona.name,
+            &format!("{}/{}", persona.model, model_version(persona)),
+            &gen_config,
+            &context_chunks,
+            &system_prompt,
+            &history,
+            &question,
+        )
+    });
+    let cached = cache_key
+        .as_deref()
+        .and_then(|key| response_cache::get(key, Duration::from_secs(args.cache_ttl)));
+
+    let mut messages = history.clone();
+    messages.push(Message {
+        role: "user".to_string(),
+        content: final_content.clone(),
+    });
+
+    let mut pipe = args.pipe_to.as_deref().map(pipe::Pipe::spawn).transpose()?;
+    let from_cache = cached.is_some();
+    // display only, it is neither sent nor part of the saved/piped response
+    let prefix = persona.response_prefix.as_deref().unwrap_or_default();
+    let response = if args.num_responses > 1 {
+        let responses = ask_candidates(model, &messages, &gen_config, args).await?;
+        for (i, response) in responses.iter().enumerate() {
+            println!("\n--- Response {} ---\n{}{}", i + 1, prefix, response);
+        }
+        responses.join("\n\n")
+    } else if let Some(response) = cached {
+        println!("\n--- Response (cached) ---\n{}{}", prefix, response);
+        response
+    } else {
+        let mut refusals = 0;
+        loop {
+            let (response, metadata) = if args.stream {
+                print!("\n--- Response Stream ---\n{}", prefix);
+                let start = Instant::now();
+                let response = print_stream_with(model, &messages, &gen_config, |chunk| {
+                    pipe.as_mut().map_or(Ok(()), |pipe| pipe.write(chunk))
+                })
+                .await?;
+                let elapsed = start.elapsed().as_secs_f64();
+                println!();
+                if verbose {
+                    let chars = response.chars().count();
+                    eprintln!(
+                        "[Stream: {} chars in {:.2}s (~{:.0} chars/sec)]",
+                        chars,
+                        elapsed,
+                        chars as f64 / elapsed.max(f64::EPSILON)
+                    );
+                }
+                (response, None)
+            } else {
+                let (response, metadata) = model.ask_with_metadata(&messages, &gen_config).await?;
+                if args.show_thinking && !metadata.thoughts.is_empty() {
+                    println!("\n--- Thinking ---\n{}", metadata.thoughts.trim_end());
+                }
+                println!("\n--- Response ---\n{}{}", prefix, response);
+                if args.include_usage {
+                    println!("\n{}", metadata);
+                }
+                (response, Some(metadata))
+            };
+            if !args.retry_on_refusal
+                || stream_cancelled()
+                || refusals == args.max_refusal_retries
+                || !is_refusal(&response, metadata.as_ref())
+            {
+                break response;
+            }
+            refusals += 1;
+            println!("[Response refused, retrying with rephrased prompt...]");
+            if let Some(last) = messages.last_mut() {
+                last.content = format!("{}\n\n{}", REFUSAL_REPHRASE_INSTRUCTION, final_content);
+            }
+        }
+    };
+    // streamed chunks were piped as they arrived
+    if let Some(pipe) = &mut pipe
+        && (from_cache || !args.stream)
+    {
+        pipe.write(&response)?;
+    }
+    if let Some(schema) = &gen_config.response_schema {
+        check_json_response(&response, schema);
+    }
+    if args.show_cost {
+        print_cost_estimate(persona, &messages, &response, from_cache);
+    }
+    if args.show_sources {
+        print_sources(&sources);
+    }
+    // hits aren't written back, so the TTL counts from the original request, and a reply
+    // cut short by Ctrl-C isn't one to serve again
+    if let Some(key) = cache_key
+        .as_deref()
+        .filter(|_| !from_cache && !stream_cancelled())
+        && let Err(e) = response_cache::put(key, &response)
+    {
+        println!("Warning: Failed to cache response: {}", e);
+    }
+
+    // the session keeps the bare question, system prompt and RAG context are rebuilt each time
+    if let Some(path) = &args.session_file {
+        history.push(Message {
+            role: "user".to_string(),
+            content: format!("User question: {}", prompt_str),
+        });
+        history.push(Message {
+            role: "assistant".to_string(),
+            content: response.clone(),
+        });
+        if let Some(max) = args.max_session_messages {
+            session::keep_last(&mut history, max);
+        }
+        session::save(path, &history)?;
+    }
+
+    let pipe_status = pipe.map(pipe::Pipe::finish).transpose()?;
+    if args.stream && stream_cancelled() {
+        return Err(CliError::Interrupted.into());
+    }
+    // exit as the piped-to command did, like the last command of a shell pipeline
+    if let Some(status) = pipe_status.filter(|status| !status.success()) {
+        return Err(CliError::PipeFailed(status.code().unwrap_or(1)).into());
+    }
+    Ok(response)
+}
+
+fn load_image(path: &Path) -> Result<InlineImage> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase);
+    let mime_type = match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => {
+            return Err(CliError::InvalidArgument(format!(
+                "Unsupported image type: {:?} (expected .jpg, .png or .webp)",
+                path
+            ))
+            .into());
+        }
+    };
+    let bytes = std::fs::read(path).with_context(|| format!("Failed to read image {:?}", path))?;
+    Ok(InlineImage {
+        mime_type: mime_type.to_string(),
+        data: base64::engine::general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+// Summary for `ask --benchmark-n`, with the first response as a sample.
+fn print_ask_benchmark(sample: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let mean = latencies.iter().map(Duration::as_secs_f64).sum::<f64>() / latencies.len() as f64;
+    println!("\n--- Sample Response (run 1) ---\n{}", sample);
+    println!("\n--- Benchmark Summary ---");
+    println!("{:<12} {:>12}", "runs", latencies.len());
+    println!("{:<12} {:>9.0} ms", "min", percentile_ms(&latencies, 0.0));
+    println!("{:<12} {:>9.0} ms", "max", percentile_ms(&latencies, 1.0));
+    println!("{:<12} {:>9.0} ms", "mean", mean * 1000.0);
+    println!("{:<12} {:>9.0} ms", "p50", percentile_ms(&latencies, 0.5));
+    println!("{:<12} {:>9.0} ms", "p95", percentile_ms(&latencies, 0.95));
+}
+
+// Nearest-rank percentile of sorted, non-empty latencies, in milliseconds.
+fn percentile_ms(sorted: &[Duration], p: f64) -> f64 {
+    let idx = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[idx].as_secs_f64() * 1000.0
+}
+
+fn print_cost_estimate(persona: &Persona, messages: &[Message], response: &str, from_cache: bool) {
+    if from_cache {
+        eprintln!("~$0 (cached response)");
+        return;
+    }
+    let version = model_version(persona);
+    let Some(pricing) = vendors::pricing(version) else {
+        eprintln!(
+            "No pricing known for model '{}', can't estimate cost",
+            version
+        );
+        return;
+    };
+    let input_tokens: usize = messages.iter().map(|m| count_tokens(&m.content)).sum();
+    let output_tokens = count_tokens(response);
+    eprintln!(
+        "~${:.4} (est. {} in + {} out tokens)",
+        pricing.cost(input_tokens, output_tokens),
+        input_tokens,
+        output_tokens
+    );
+}
+
+// "<vendor>/<version>" for `ask --model-override`, the version may contain more slashes.
+fn parse_model_override(value: &str) -> Result<(String, String), String> {
+    match value.split_once('/') {
+        Some((vendor, version)) if !vendor.is_empty() && !version.is_empty() => {
+            Ok((vendor.to_string(), version.to_string()))
+        }
+        _ => Err("expected <vendor>/<version>, e.g. gemini/gemini-1.5-pro".to_string()),
+    }
+}
+
+// Why `ask --fallback-model` should take over after `e`, if it should.
+fn fallback_reason(e: &anyhow::Error) -> Option<&'static str> {
+    match e.downcast_ref::<VendorError>()? {
+        VendorError::ApiError { status: 429, .. } => Some("rate limit"),
+        VendorError::ApiError { status: 503, .. } => Some("service unavailable"),
+        _ => None,
+    }
+}
+
+// The prompt rephrased by `model` for `ask --rewrite-prompt`.
+async fn rewrite_prompt(
+    model: &dyn LanguageModel,
+    config: &GenerationConfig,
+    prompt: &str,
+) -> Result<String> {
+    let messages = vec![Message {
+        role: "user".to_string(),
+        content: format!(
+            "Rewrite the following prompt to be clearer and more precise, keeping its intent. Reply with the rewritten prompt only.\n\nPrompt: {}",
+            prompt
+        ),
+    }];
+    let rewritten = model.ask(&messages, config).await?;
+    let rewritten = rewritten.trim().trim_matches('"').trim();
+    if rewritten.is_empty() {
+        return Err(anyhow!("The model returned an empty rewritten prompt"));
+    }
+    Ok(rewritten.to_string())
+}
+
+// The --num-responses candidates. Streams carry only one candidate, so --stream makes
+// separate (non-streaming) requests instead.
+async fn ask_candidates(
+    model: &dyn LanguageModel,
+    messages: &[Message],
+    config: &GenerationConfig,
+    args: &AskArgs,
+) -> Result<Vec<String>> {
+    let mut n = args.num_responses;
+    if n > MAX_NUM_RESPONSES {
+        println!(
+            "Warning: --num-responses {} is above the maximum, using {}",
+            n, MAX_NUM_RESPONSES
+        );
+        n = MAX_NUM_RESPONSES;
+    }
+    if !args.stream {
+        return Ok(model.ask_candidates(messages, config, n).await?);
+    }
+    let mut responses = Vec::with_capacity(n);
+    for _ in 0..n {
+        responses.push(model.ask(messages, config).await?);
+    }
+    Ok(responses)
+}
+
+// The persona's system prompt with --system-prompt-override/--system-prompt-append applied.
+fn ask_system_prompt(args: &AskArgs, persona: &Persona) -> String {
+    match (&args.system_prompt_override, &args.system_prompt_append) {
+        (Some(system_prompt), _) => system_prompt.clone(),
+        (None, Some(extra)) => format!("{}\n\n{}", persona.system_prompt, extra),
+        (None, None) => persona.system_prompt.clone(),
+    }
+}
+
+// The prompt args, filled into the persona's --template if one is given.
+fn ask_prompt(args: &AskArgs, persona: &Persona) -> Result<String> {
+    let prompt = args.prompt.join(" ");
+    let Some(name) = &args.template else {
+        return Ok(prompt);
+    };
+    let template = persona.prompt_templates.get(name).ok_or_else(|| {
+        CliError::InvalidArgument(format!(
+            "Persona '{}' has no prompt template named '{}'",
+            persona.name, name
+        ))
+    })?;
+    Ok(template.replace("{prompt}", &prompt))
+}
+
+// The user message `ask` sends: system prompt, retrieved context and the question.
+fn ask_content(system_prompt: &str, context_chunks: &[String], prompt: &str) -> String {
+    format!(
+        "{}\n\n{}\n\nUser question: {}",
+        system_prompt,
+        ask_context_block(context_chunks),
+        prompt
+    )
+}
+
+fn ask_context_block(context_chunks: &[String]) -> String {
+    if context_chunks.is_empty() {
+        return String::new();
+    }
+    format!(
+        "Here is some relevant context from the local files:\n\n{}\n",
+        context_chunks.join("\n")
+    )
+}
+
+// `ask --explain`: every input to the prompt, then the prompt with each part delimited.
+fn print_explanation(
+    persona: &Persona,
+    system_prompt: &str,
+    prompt: &str,
+    context_chunks: &[String],
+    scores: &[Option<f32>],
+    history_len: usize,
+) {
+    println!(
+        "\n=== 1. Persona system prompt ===\n{}",
+        persona.system_prompt
+    );
+    if system_prompt != persona.system_prompt {
+        println!("(replaced or extended on the command line, see the final prompt)");
+    }
+    println!("\n=== 2. RAG query ===\n{}", prompt);
+    println!("\n=== 3. Retrieved context ({}) ===", context_chunks.len());
+    for (i, (chunk, score)) in context_chunks.iter().zip(scores).enumerate() {
+        match score {
+            Some(score) => println!("[{}] similarity {:.4}\n{}", i + 1, score, chunk),
+            None => println!("[{}] raw context, not ranked\n{}", i + 1, chunk),
+        }
+    }
+    println!("\n=== 4. Final prompt ===");
+    if history_len > 0 {
+        println!("(sent after {} earlier session message(s))", history_len);
+    }
+    println!(
+        "[SYSTEM_PROMPT_START]\n{}\n[SYSTEM_PROMPT_END]\n\n[CONTEXT_START]\n{}\n[CONTEXT_END]\n\n[USER_QUESTION_START]\nUser question: {}\n[USER_QUESTION_END]",
+        system_prompt,
+        ask_context_block(context_chunks),
+        prompt
+    );
+}
+
+// The schema from --json-schema or --json-schema-inline, if either is given.
+fn ask_json_schema(args: &AskArgs) -> Result<Option<serde_json::Value>> {
+    let (schema, origin) = match (&args.json_schema, &args.json_schema_inline) {
+        (Some(path), _) => (
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read JSON schema: {:?}", path))?,
+            format!("{:?}", path),
+        ),
+        (None, Some(inline)) => (inline.clone(), "--json-schema-inline".to_string()),
+        (None, None) => return Ok(None),
+    };
+    serde_json::from_str(&schema).map(Some).map_err(|e| {
+        CliError::InvalidArgument(format!("Invalid JSON schema in {}: {}", origin, e)).into()
+    })
+}
+
+// Warns when a response that should follow `schema` isn't JSON or doesn't match it.
+// A ```json fence around it is tolerated, vendors without schema support like to add one.
+fn check_json_response(response: &str, schema: &serde_json::Value) {
+    let json = response.trim();
+    let json = json
+        .strip_prefix("```json")
+        .or_else(|| json.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(json);
+    let value: serde_json::Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(e) => {
+            println!("Warning: The response is not valid JSON: {}", e);
+            return;
+        }
+    };
+    let violations = json_schema::violations(&value, schema);
+    if !violations.is_empty() {
+        println!("Warning: The response doesn't match the JSON schema:");
+        for violation in violations {
+            println!("  - {}", violation);
+        }
+    }
+}
+
+// An empty response, or one the vendor says ended for a reason other than finishing or
+// running out of tokens, e.g. Gemini's SAFETY or RECITATION.
+fn is_refusal(response: &str, metadata: Option<&ResponseMetadata>) -> bool {
+    response.trim().is_empty()
+        || metadata
+            .and_then(|metadata| metadata.finish_reason.as_deref())
+            .is_some_and(|reason| !matches!(reason, "STOP" | "MAX_TOKENS"))
+}
+
+// The context chunk to drop first when the prompt is too long: the least similar
+// retrieved one, whichever store it came from. Raw --context snippets have no score
+// (None) and go last, the latest first.
+fn least_relevant(scores: &[Option<f32>]) -> Option<usize> {
+    scores
+        .iter()
+        .enumerate()
+        .filter_map(|(i, score)| score.map(|score| (i, score)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .or_else(|| scores.len().checked_sub(1))
+}
+
+// A search result as it appears in a prompt's context block.
+fn context_snippet(result: &SearchResult) -> String {
+    format!(
+        "---\nSource: {}\n```\n{}\n```\n",
+        result.source, result.text
+    )
+}
+
+fn print_sources(sources: &[SearchResult]) {
+    if sources.is_empty() {
+        println!("\nSources: none");
+        return;
+    }
+    println!("\nSources:");
+    for source in sources {
+        println!(
+            "  {} (chunk #{}, similarity {:.4})",
+            source.source, source.chunk_index, source.similarity
+        );
+    }
+}
+
+// Asks a yes/no question on stdin, anything but y/yes is a no.
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Lets the user change `text` in `editor` (a command, possibly with arguments) and
+// returns the saved file.
+fn edit_in_editor(editor: &str, text: &str) -> Result<String> {
+    let mut file = tempfile::Builder::new()
+        .prefix("aiterm-prompt-")
+        .suffix(".md")
+        .tempfile()
+        .context("Failed to create temporary file")?;
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or_else(|| anyhow!("EDITOR is empty"))?;
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(file.path())
+        .status()
+        .with_context(|| format!("Failed to start editor '{}'", editor))?;
+    if !status.success() {
+        return Err(anyhow!("Editor '{}' exited with {}", editor, status));
+    }
+    std::fs::read_to_string(file.path()).context("Failed to read back the edited prompt")
+}
+
+async fn run_compare(args: &AskArgs, verbose: bool) -> Result<()> {
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let prompt_str = args.prompt.join(" ");
+    let (first, second) = (&args.compare[0], &args.compare[1]);
+    println!("\nAsking {} and {}: {}...", first, second, prompt_str);
+
+    let embedding_cache = EmbeddingCache::shared(EMBEDDING_CACHE_CAPACITY);
+    let (first_response, second_response) = tokio::try_join!(
+        compare_answer(first, args, &api_key, &embedding_cache, verbose),
+        compare_answer(second, args, &api_key, &embedding_cache, verbose),
+    )?;
+
+    let mut table = comfy_table::Table::new();
+    match table.width() {
+        Some(width) if width >= 80 => {
+            table
+                .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+                .set_header(vec![first, second])
+                .add_row(vec![first_response.trim(), second_response.trim()]);
+            println!("\n{}", table);
+        }
+        // too narrow (or not a terminal) for two readable columns
+        _ => {
+            println!("\n--- {} ---\n{}", first, first_response.trim());
+            println!("\n--- {} ---\n{}", second, second_response.trim());
+        }
+    }
+    Ok(())
+}
+
+async fn compare_answer(
+    name: &str,
+    args: &AskArgs,
+    api_key: &str,
+    cache: &SharedEmbeddingCache,
+    verbose: bool,
+) -> Result<String> {
+    let persona = config::load_persona(name)?;
+    let prompt = ask_prompt(args, &persona)?;
+    persona_answer(&persona, &prompt, args, api_key, cache, verbose).await
+}
+
+// `ask --chain`: the first persona gets the prompt, every later one the response before it.
+async fn run_chain(args: &AskArgs, verbose: bool) -> Result<()> {
+    if args.chain.len() < 2 {
+        return Err(CliError::InvalidArgument(
+            "--chain needs at least two personas, e.g. --chain extract,summarize".to_string(),
+        )
+        .into());
+    }
+    // fail on a typo before the first step is paid for
+    let personas = args
+        .chain
+        .iter()
+        .map(|name| config::load_persona(name))
+        .collect::<Result<Vec<_>>>()?;
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let embedding_cache = EmbeddingCache::shared(EMBEDDING_CACHE_CAPACITY);
+
+    let mut response = String::new();
+    for (i, persona) in personas.iter().enumerate() {
+        let prompt = if i == 0 {
+            ask_prompt(args, persona)?
+        } else {
+            std::mem::take(&mut response)
+        };
+        if i > 0 {
+            print!(" → ");
+        }
+        print!("Step {}/{} [{}]", i + 1, personas.len(), persona.name);
+        io::stdout().flush()?;
+        response = persona_answer(persona, &prompt, args, &api_key, &embedding_cache, verbose)
+            .await?
+            .trim()
+            .to_string();
+        if verbose {
+            eprintln!("\n[Step {} response]\n{}", i + 1, response);
+        }
+    }
+    println!("\n\n--- Response ---\n{}", response);
+    Ok(())
+}
+
+// One request to `persona` with its own RAG context, no session or cache.
+async fn persona_answer(
+    persona: &Persona,
+    prompt: &str,
+    args: &AskArgs,
+    api_key: &str,
+    cache: &SharedEmbeddingCache,
+    verbose: bool,
+) -> Result<String> {
+    let model = build_model(persona, api_key, verbose)?;
+    let mut context_chunks = Vec::new();
+    if !args.no_rag
+        && let Some(store) = build_rag_store(persona, api_key, Some(cache), None).await?
+    {
+        context_chunks = store
+            .search(prompt, args.rag_chunks)
+            .await?
+            .iter()
+            .map(context_snippet)
+            .collect();
+    }
+
+    let messages = vec![Message {
+        role: "user".to_string(),
+        content: ask_content(&ask_system_prompt(args, persona), &context_chunks, prompt),
+    }];
+    model
+        .ask(&messages, &generation_config(persona))
+        .await
+        .map_err(|e| anyhow!("{}: {}", persona.name, e))
+}
+
+async fn run_ask_with_tools(args: AskWithToolsArgs, verbose: bool) -> Result<()> {
+    let persona = config::load_persona(&args.persona)?;
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let model = build_model(&persona, &api_key, verbose)?;
+    let tools = offered_tools(&persona);
+
+    let prompt_str = args.prompt.join(" ");
+    println!("\nAsking: {}...", prompt_str);
+    let mut messages = vec![Message {
+        role: "user".to_string(),
+        content: format!("{}\n\nUser question: {}", persona.system_prompt, prompt_str),
+    }];
+    if tools.is_empty() {
+        println!(
+            "Warning: Persona '{}' hasn't opted in to any tools (allowed_tools is empty), asking without them.",
+            persona.name
+        );
+        let text = model.ask(&messages, &generation_config(&persona)).await?;
+        println!("\n--- Response ---\n{}", text);
+        return Ok(());
+    }
+    // tool calls and their results go back to the model as plain text turns
+    for _ in 0..MAX_TOOL_CALLS {
+        match model.ask_with_tools(&messages, &tools).await? {
+            AskWithToolsResult::Text(text) => {
+                println!("\n--- Response ---\n{}", text);
+                return Ok(());
+            }
+            AskWithToolsResult::ToolCall(call) => {
+                println!("[Tool call: {}({})]", call.name, call.arguments);
+                // only what was offered runs, whatever the model asks for
+                let output = if tools.iter().any(|tool| tool.name == call.name) {
+                    run_tool(&call)
+                } else {
+                    println!(
+                        "Warning: The model called '{}', which wasn't offered to it. Not running it.",
+                        call.name
+                    );
+                    format!("Error: tool '{}' is not available", call.name)
+                };
+                messages.push(Message {
+                    role: "assistant".to_string(),
+                    content: format!("Calling {}({})", call.name, call.arguments),
+                });
+                messages.push(Message {
+                    role: "user".to_string(),
+                    content: format!("Result of {}:\n{}", call.name, output),
+                });
+            }
+        }
+    }
+    Err(anyhow!(
+        "No answer after {} tool calls, giving up.",
+        MAX_TOOL_CALLS
+    ))
+}
+
+// The built-in tools that the persona's allowed_tools lists, nothing else is sent to
+// the API.
+fn offered_tools(persona: &Persona) -> Vec<ToolDefinition> {
+    let tools = [ToolDefinition {
+        name: "read_file".to_string(),
+        description: "Read a text file from the local file system.".to_string(),
+        parameters: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Path of the file to read" }
+            },
+            "required": ["path"]
+        }),
+    }];
+    for name in &persona.allowed_tools {
+        if !tools.iter().any(|tool| &tool.name == name) {
+            println!(
+                "Warning: Unknown tool '{}' in allowed_tools of persona '{}'.",
+                name, persona.name
+            );
+        }
+    }
+    tools
+        .into_iter()
+        .filter(|tool| persona.allowed_tools.contains(&tool.name))
+        .collect()
+}
+
+// Errors are returned as the tool's output so the model can react to them.
+fn run_tool(call: &ToolCall) -> String {
+    match call.name.as_str() {
+        "read_file" => match call.arguments.get("path").and_then(|path| path.as_str()) {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(content) => content.chars().take(TOOL_OUTPUT_MAX_CHARS).collect(),
+                Err(e) => format!("Error: failed to read {}: {}", path, e),
+            },
+            None => "Error: missing 'path' argument".to_string(),
+        },
+        other => format!("Error: unknown tool '{}'", other),
+    }
+}
+
+async fn load_agent(
+    name: &str,
+    api_key: &str,
+    no_rag: bool,
+    cache: &SharedEmbeddingCache,
+    verbose: bool,
+) -> Result<Agent> {
+    let persona = config::load_persona(name)?;
+    let model = build_model(&persona, api_key, verbose)?;
+    let rag_store = if no_rag {
+        None
+    } else {
+        build_rag_store(&persona, api_key, Some(cache), None).await?
+    };
+    Ok(Agent {
+        persona,
+        model,
+        rag_store,
+    })
+}
+
+async fn run_converse(args: ConverseArgs, verbose: bool) -> Result<()> {
+    println!("Starting a conversation with: {}", args.persona.join(", "));
+    // fail before the conversation rather than after it
+    if let Some(name) = &args.write_persona {
+        let persona_file = config::get_persona_file(name)?;
+        if persona_file.exists() {
+            return Err(anyhow!(
+                "Persona '{}' already exists: {:?}",
+                name,
+                persona_file
+            ));
+        }
+    }
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+
+    // load agents, personas with overlapping context share embeddings
+    let embedding_cache = EmbeddingCache::shared(EMBEDDING_CACHE_CAPACITY);
+    let mut agents = Vec::new();
+    for p_name in &args.persona {
+        agents.push(load_agent(p_name, &api_key, args.no_rag, &embedding_cache, verbose).await?);
+    }
+    let observer = match &args.observer {
+        Some(name) => {
+            Some(load_agent(name, &api_key, args.no_rag, &embedding_cache, verbose).await?)
+        }
+        None => None,
+    };
+    let summarizer = match &args.summary_model {
+        Some(name) => Some(load_agent(name, &api_key, true, &embedding_cache, verbose).await?),
+        None => None,
+    };
+
+    converse(&args, &agents, observer.as_ref(), summarizer.as_ref()).await?;
+    if stream_cancelled() {
+        return Err(CliError::Interrupted.into());
+    }
+    Ok(())
+}
+
+// Runs the turns and the observer summary with already loaded agents. `summarizer`
+// defaults to the first agent.
+async fn converse(
+    args: &ConverseArgs,
+    agents: &[Agent],
+    observer: Option<&Agent>,
+    summarizer: Option<&Agent>,
+) -> Result<Transcript> {
+    let summarizer = summarizer.unwrap_or(&agents[0]);
+    validate_injected_turns(&args.inject_turn, args.turns, agents)?;
+    // initialize converse
+    let initial_prompt = args.prompt.join(" ");
+    let mut history = ConversationHistory::new(match &args.seed_file {
+        Some(path) => seeded_intro(&read_turn_records(path)?, &initial_prompt),
+        None => format!(
+            "The user started the conversation with this prompt: \"{}\"",
+            initial_prompt
+        ),
+    });
+    let mut transcript = Transcript::new(args.persona.clone(), args.turns, initial_prompt);
+    let mut turn_log = TurnLog::new(args.transcript_format, args.transcript_file.as_deref())?;
+    // other formats keep stdout for the transcript, so progress and warnings go to stderr
+    let live = args.transcript_format == TranscriptFormat::Text;
+    let status = |message: String| {
+        if live {
+            println!("{}", message);
+        } else {
+            eprintln!("{}", message);
+        }
+    };
+
+    // each agent keeps its color for the whole conversation
+    let colored = live && !args.no_color && io::stdout().is_terminal();
+    let agent_color = |index: usize| colored.then(|| AGENT_COLORS[index % AGENT_COLORS.len()]);
+
+    let schedule = agent_schedule(&args.agent_weights, agents)?;
+
+    // go
+    let mut next_agent_index = schedule.as_ref().map_or(0, |schedule| schedule[0]);
+    for i in 0..args.turns {
+        let injected = args.inject_turn.iter().find(|t| t.turn == i + 1);
+        let current_agent_index = match injected {
+            Some(injected) => agent_index(agents, &injected.agent)
+                .ok_or_else(|| anyhow!("Unknown agent '{}' in --inject-turn", injected.agent))?,
+            None => next_agent_index,
+        };
+        // round-robin (or --agent-weights) unless a directed turn names someone else
+        next_agent_index = match &schedule {
+            Some(schedule) => schedule[(i + 1) % schedule.len()],
+            None => (current_agent_index + 1) % agents.len(),
+        };
+        let agent = &agents[current_agent_index];
+        let color = agent_color(current_agent_index);
+
+        if live {
+            println!(
+                "\n--- Turn {}/{} | Speaking: {} ---",
+                i + 1,
+                args.turns,
+                paint(&agent.persona.name, color)
+            );
+        }
+
+        let full_response = if let Some(injected) = injected {
+            if live {
+                println!("[injected] {}", paint(&injected.text, color));
+            }
+            injected.text.clone()
+        } else {
+            fit_history(&mut history, args, summarizer, &status).await?;
+            let conversation_history = history.render();
+
+            // RAG search for the current turn based on the latest history
+            let context_str = if let Some(store) = &agent.rag_store {
+                let results = store.search(&conversation_history, args.rag_chunks).await?;
+                if !results.is_empty() {
+                    let context_chunks: Vec<String> = results.iter().map(context_snippet).collect();
+                    format!("CONTEXT:\n{}\n", context_chunks.join("\n"))
+                } else {
+                    String::new()
+                }
+            } else {
+                String::new()
+            };
+
+            let directed_str = if args.directed {
+                let others: Vec<&str> = agents
+                    .iter()
+                    .filter(|a| a.persona.name != agent.persona.name)
+                    .map(|a| a.persona.name.as_str())
+                    .collect();
+                format!(
+                    " End your response with a final line containing only @<Name> of who should speak next, one of: {}.",
+                    others.join(", ")
+                )
+            } else {
+                String::new()
+            };
+
+            // abother prompt for this turn
+            let turn_prompt = format!(
+                "YOUR ROLE:\n{system_prompt}\n\n{context}\n\nCONVERSATION HISTORY:\n---\n{history}\n---\n\nINSTRUCTIONS: Your name is {name}. Based on your role and the history, provide your response. Do NOT include your name or role in the response itself. Just give your conversational reply.{directed}",
+                system_prompt = agent.persona.system_prompt,
+                context = context_str,
+                history = conversation_history,
+                name = agent.persona.name,
+                directed = directed_str
+            );
+
+            let messages = vec![Message {
+                role: "user".to_string(),
+                content: turn_prompt,
+            }];
+
+            // agent's response
+            let gen_config = generation_config(&agent.persona);
+            let response = async {
+                if live {
+                    if let Some(color) = color {
+                        print!("\x1b[{}m", color);
+                    }
+                    let response = print_stream(agent.model.as_ref(), &messages, &gen_config).await;
+                    if color.is_some() {
+                        print!("\x1b[0m");
+                    }
+                    response
+                } else {
+                    Ok(agent.model.ask(&messages, &gen_config).await?)
+                }
+            };
+            match args.turns_timeout {
+                Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), response).await
+                {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        // the stream was cut off before it could reset the color
+                        if color.is_some() {
+                            print!("\x1b[0m");
+                        }
+                        status(format!(
+                            "\n[Turn {} timed out for {}, skipping]",
+                            i + 1,
+                            agent.persona.name
+                        ));
+                        history.push(
+                            i + 1,
+                            format!(
+                                "\n\n[{} did not respond in time and was skipped]",
+                                agent.persona.name
+                            ),
+                        );
+                        continue;
+                    }
+                },
+                None => response.await?,
+            }
+        };
+
+        // update history
+        history.push(
+            i + 1,
+            format!("\n\n{}: {}", agent.persona.name, full_response.trim()),
+        );
+        transcript.push(&agent.persona.name, full_response.trim());
+        turn_log.turn(Some(i + 1), &agent.persona.name, full_response.trim())?;
+        // the observer and the remaining turns are skipped, the transcript is still saved
+        if live && stream_cancelled() {
+            save_transcript(args, &transcript)?;
+            return Ok(transcript);
+        }
+
+        if args.directed {
+            match mentioned_agent(&full_response, agents) {
+                Some(index) => next_agent_index = index,
+                None => status(format!(
+                    "\n[Warning: {} did not pass the turn to a known agent, using round-robin]",
+                    agent.persona.name
+                )),
+            }
+        }
+    }
+
+    if live {
+        println!("\n\n--- Conversation Finished ---");
+    }
+    // before the observer, so a failing observer doesn't cost the conversation
+    save_transcript(args, &transcript)?;
+
+    if let Some(observer) = observer {
+        fit_history(&mut history, args, summarizer, &status).await?;
+        let conversation_history = history.render();
+        let context_str = if let Some(store) = &observer.rag_store {
+            let results = store.search(&conversation_history, args.rag_chunks).await?;
+            if !results.is_empty() {
+                let context_chunks: Vec<String> = results.iter().map(context_snippet).collect();
+                format!("CONTEXT:\n{}\n", context_chunks.join("\n"))
+            } else {
+                String::new()
+            }
+        } else {
+            String::new()
+        };
+        let summary_prompt = format!(
+            "YOUR ROLE:\n{system_prompt}\n\n{context}\n\nCONVERSATION:\n---\n{history}\n---\n\nINSTRUCTIONS: You observed the conversation above without taking part. Summarize it according to your role.",
+            system_prompt = observer.persona.system_prompt,
+            context = context_str,
+            history = conversation_history,
+        );
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: summary_prompt,
+        }];
+
+        let gen_config = generation_config(&observer.persona);
+        let summary = if live {
+            println!("\n--- Observer Summary ---");
+            let summary = print_stream(observer.model.as_ref(), &messages, &gen_config).await?;
+            println!();
+            summary
+        } else {
+            observer.model.ask(&messages, &gen_config).await?
+        };
+        turn_log.turn(None, &observer.persona.name, summary.trim())?;
+    }
+
+    if let Some(name) = &args.write_persona {
+        let persona_file = write_synthesized_persona(name, &agents[0], &history.render()).await?;
+        status(format!(
+            "\nWrote persona '{}' to {}",
+            name,
+            persona_file.display()
+        ));
+    }
+
+    Ok(transcript)
+}
+
+fn save_transcript(args: &ConverseArgs, transcript: &Transcript) -> Result<()> {
+    if let Some(path) = &args.save_transcript {
+        transcript.save(path)?;
+        println!("Transcript saved to {}", path.display());
+    }
+    Ok(())
+}
+
+// `text` in an ANSI color, or as it is for None.
+fn paint(text: &str, color: Option<&str>) -> String {
+    match color {
+        Some(code) => format!("\x1b[{}m{}\x1b[0m", code, text),
+        None => text.to_string(),
+    }
+}
+
+// Asks the first agent's model to turn the conversation into a system prompt and saves
+// it as a new persona with that agent's model and context.
+async fn write_synthesized_persona(
+    name: &str,
+    template: &Agent,
+    conversation_history: &str,
+) -> Result<PathBuf> {
+    let messages = vec![Message {
+        role: "user".to_string(),
+        content: format!(
+            "CONVERSATION:\n---\n{}\n---\n\nSummarize the above discussion into a single system prompt for an AI assistant. Reply with the system prompt only.",
+            conversation_history
+        ),
+    }];
+    println!("\n--- Synthesizing persona '{}' ---", name);
+    let system_prompt = template
+        .model
+        .ask(&messages, &generation_config(&template.persona))
+        .await?;
+
+    let mut persona = toml::Table::new();
+    persona.insert("name".into(), name.into());
+    persona.insert("model".into(), template.persona.model.as_str().into());
+    if let Some(model_version) = &template.persona.model_version {
+        persona.insert("model_version".into(), model_version.as_str().into());
+    }
+    persona.insert("system_prompt".into(), system_prompt.trim().into());
+    persona.insert(
+        "context_paths".into(),
+        template.persona.context_paths.clone().into(),
+    );
+
+    let persona_file = config::get_persona_file(name)?;
+    if persona_file.exists() {
+        return Err(anyhow!(
+            "Persona '{}' already exists: {:?}",
+            name,
+            persona_file
+        ));
+    }
+    std::fs::write(&persona_file, toml::to_string(&persona)?)
+        .map_err(|e| anyhow!("Failed to write persona file {:?}: {}", persona_file, e))?;
+    Ok(persona_file)
+}
+
+// The converse history: the user's prompt followed by one entry per turn.
+struct ConversationHistory {
+    intro: String,
+    // (turn number, entry)
+    turns: Vec<(usize, String)>,
+}
+
+impl ConversationHistory {
+    fn new(intro: String) -> Self {
+        Self {
+            intro,
+            turns: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, turn: usize, entry: String) {
+        self.turns.push((turn, entry));
+    }
+
+    fn render(&self) -> String {
+        let mut history = self.intro.clone();
+        for (_, entry) in &self.turns {
+            history.push_str(entry);
+        }
+        history
+    }
+
+    fn tokens(&self) -> usize {
+        count_tokens(&self.intro)
+            + self
+                .turns
+                .iter()
+                .map(|(_, entry)| count_tokens(entry))
+                .sum::<usize>()
+    }
+
+    // Drops the oldest turns until the estimated token count fits, the user's prompt is
+    // always kept. Returns the first and last turn removed.
+    fn truncate(&mut self, max_tokens: usize) -> Option<(usize, usize)> {
+        let mut tokens = self.tokens();
+        let mut removed = 0;
+        while removed < self.turns.len() && tokens > max_tokens {
+            tokens -= count_tokens(&self.turns[removed].1);
+            removed += 1;
+        }
+        if removed == 0 {
+            return None;
+        }
+        let (first, last) = (self.turns[0].0, self.turns[removed - 1].0);
+        self.turns.drain(..removed);
+        Some((first, last))
+    }
+
+    // Replaces the oldest half of the turns with one summary entry written by `model`.
+    // Returns the first and last turn summarized.
+    async fn summarize_oldest(
+        &mut self,
+        model: &dyn LanguageModel,
+        config: &GenerationConfig,
+    ) -> Result<Option<(usize, usize)>> {
+        let count = self.turns.len().div_ceil(2);
+        if count == 0 {
+            return Ok(None);
+        }
+        let oldest: String = self.turns[..count]
+            .iter()
+            .map(|(_, entry)| entry.as_str())
+            .collect();
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: format!(
+                "Summarize the following conversation concisely:\n\n{}",
+                oldest.trim()
+            ),
+        }];
+        let summary = model.ask(&messages, config).await?;
+        let (first, last) = (self.turns[0].0, self.turns[count - 1].0);
+        self.turns.splice(
+            ..count,
+            [(
+                last,
+                format!(
+                    "\n\n[Summary of turns {}-{}: {}]",
+                    first,
+                    last,
+                    summary.trim()
+                ),
+            )],
+        );
+        Ok(Some((first, last)))
+    }
+}
+
+// Brings the history under --max-history-tokens, summarizing before truncating with
+// --summarize-history. A summary that is still too long is truncated like any turn.
+async fn fit_history(
+    history: &mut ConversationHistory,
+    args: &ConverseArgs,
+    summarizer: &Agent,
+    status: &impl Fn(String),
+) -> Result<()> {
+    let Some(max_tokens) = args.max_history_tokens else {
+        return Ok(());
+    };
+    if args.summarize_history
+        && history.tokens() > max_tokens
+        && let Some((first, last)) = history
+            .summarize_oldest(
+                summarizer.model.as_ref(),
+                &generation_config(&summarizer.persona),
+            )
+            .await?
+    {
+        status(format!(
+            "\n[History summarized: turns {}-{} replaced by a summary]",
+            first, last
+        ));
+    }
+    if let Some((first, last)) = history.truncate(max_tokens) {
+        status(format!(
+            "\n[History truncated: removed turns {}-{} to fit context window]",
+            first, last
+        ));
+    }
+    Ok(())
+}
+
+// The history of a conversation continued from a saved transcript. The observer's summary
+// isn't part of the conversation and is left out.
+fn seeded_intro(records: &[TurnRecord], prompt: &str) -> String {
+    let mut intro = "This conversation continues an earlier one:".to_string();
+    for record in records.iter().filter(|record| record.turn.is_some()) {
+        intro.push_str(&format!("\n\n{}: {}", record.agent, record.response));
+    }
+    if !prompt.is_empty() {
+        intro.push_str(&format!(
+            "\n\nThe user continued the conversation with this prompt: \"{}\"",
+            prompt
+        ));
+    }
+    intro
+}
+
+// A `converse --inject-turn` response, the turn is 1-based.
+#[derive(Debug, Clone)]
+struct InjectedTurn {
+    turn: usize,
+    agent: String,
+    text: String,
+}
+
+fn parse_injected_turn(value: &str) -> Result<InjectedTurn, String> {
+    let mut parts = value.splitn(3, ':');
+    let (Some(turn), Some(agent), Some(text)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("expected <turn>:<agent>:<text>".to_string());
+    };
+    let turn = turn
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid turn number '{}'", turn))?;
+    Ok(InjectedTurn {
+        turn,
+        agent: agent.trim().to_string(),
+        text: text.trim().to_string(),
+    })
+}
+
+// Checked up front, so a typo doesn't surface halfway through the conversation.
+fn validate_injected_turns(
+    injected: &[InjectedTurn],
+    turns: usize,
+    agents: &[Agent],
+) -> Result<()> {
+    for (i, injected_turn) in injected.iter().enumerate() {
+        if !(1..=turns).contains(&injected_turn.turn) {
+            return Err(CliError::InvalidArgument(format!(
+                "--inject-turn {} is out of range, the conversation has {} turns",
+                injected_turn.turn, turns
+            ))
+            .into());
+        }
+        if agent_index(agents, &injected_turn.agent).is_none() {
+            return Err(CliError::InvalidArgument(format!(
+                "--inject-turn names unknown agent '{}'",
+                injected_turn.agent
+            ))
+            .into());
+        }
+        if injected[..i].iter().any(|t| t.turn == injected_turn.turn) {
+            return Err(CliError::InvalidArgument(format!(
+                "--inject-turn {} is given more than once",
+                injected_turn.turn
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+fn parse_agent_weight(value: &str) -> Result<(String, usize), String> {
+    let (name, weight) = value
+        .split_once('=')
+        .ok_or_else(|| "expected <name>=<weight>".to_string())?;
+    match weight.trim().parse() {
+        Ok(weight) if weight > 0 => Ok((name.trim().to_string(), weight)),
+        _ => Err(format!(
+            "invalid weight '{}', expected a positive number",
+            weight
+        )),
+    }
+}
+
+// The order agents speak in for `--agent-weights`, repeated for as many turns as there
+// are. Each agent appears as often as its weight and is spread out over the cycle
+// (smooth weighted round-robin), so "a=3,b=1" gives a, a, b, a. None without weights.
+fn agent_schedule(weights: &[(String, usize)], agents: &[Agent]) -> Result<Option<Vec<usize>>> {
+    if weights.is_empty() {
+        return Ok(None);
+    }
+    let mut weighted: Vec<(usize, usize)> = Vec::new();
+    for (name, weight) in weights {
+        let index = agent_index(agents, name).ok_or_else(|| {
+            CliError::InvalidArgument(format!("--agent-weights names unknown agent '{}'", name))
+        })?;
+        if weighted.iter().any(|&(i, _)| i == index) {
+            return Err(CliError::InvalidArgument(format!(
+                "--agent-weights gives '{}' more than once",
+                name
+            ))
+            .into());
+        }
+        weighted.push((index, *weight));
+    }
+    if let Some(missing) = agents
+        .iter()
+        .enumerate()
+        .find(|(i, _)| !weighted.iter().any(|(index, _)| index == i))
+    {
+        return Err(CliError::InvalidArgument(format!(
+            "--agent-weights has no weight for '{}'",
+            missing.1.persona.name
+        ))
+        .into());
+    }
+    weighted.sort();
+
+    let total: usize = weighted.iter().map(|&(_, weight)| weight).sum();
+    let mut current = vec![0isize; weighted.len()];
+    let mut schedule = Vec::with_capacity(total);
+    for _ in 0..total {
+        for (credit, &(_, weight)) in current.iter_mut().zip(&weighted) {
+            *credit += weight as isize;
+        }
+        // the first of equals wins, so ties go to the agent listed first in --persona
+        let best = (0..weighted.len())
+            .max_by_key(|&i| (current[i], std::cmp::Reverse(i)))
+            .unwrap_or_default();
+        current[best] -= total as isize;
+        schedule.push(weighted[best].0);
+    }
+    Ok(Some(schedule))
+}
+
+fn agent_index(agents: &[Agent], name: &str) -> Option<usize> {
+    agents
+        .iter()
+        .position(|a| a.persona.name.eq_ignore_ascii_case(name))
+}
+
+// Index of the agent named by an "@Name" last line, for `converse --directed`.
+fn mentioned_agent(response: &str, agents: &[Agent]) -> Option<usize> {
+    let last_line = response
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())?;
+    let name = last_line
+        .trim()
+        .strip_prefix('@')?
+        .trim_end_matches(['.', ',', '!', '?']);
+    agent_index(agents, name)
+}
+
+async fn run_chat(args: ChatArgs, verbose: bool) -> Result<()> {
+    let persona = config::load_persona(&persona_or_default(args.persona.as_deref())?)?;
+    println!(
+        "Chatting with '{}' (Model: {}). Send an empty line or /quit to exit,\n/add <file> or /forget <source prefix> to change the context.",
+        persona.name, persona.model
+    );
+
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+
+    let mut rag_store = build_rag_store(&persona, &api_key, None, None).await?;
+    let model = build_model(&persona, &api_key, verbose)?;
+    let gen_config = generation_config(&persona);
+
+    // plain user/assistant turns, RAG context is only attached to the message being sent
+    let mut history: Vec<Message> = Vec::new();
+    let mut lines = io::stdin().lines();
+    let mut last_refresh = Instant::now();
+
+    loop {
+        print!("\n> ");
+        io::stdout().flush()?;
+        let input = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let input = input.trim();
+        if input.is_empty() || input == "/quit" {
+            break;
+        }
+        if let Some(path) = input.strip_prefix("/add ") {
+            let path = path.trim();
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) => {
+                    println!("Failed to read {}: {}", path, e);
+                    continue;
+                }
+            };
+            let store = match &mut rag_store {
+                Some(store) => store,
+                None => rag_store.insert(RagStore::builder(api_key.clone(), &[]).build().await?),
+            };
+            store.add_document(path.to_string(), text).await?;
+            println!("Added {} to the context.", path);
+            continue;
+        }
+        if let Some(prefix) = input.strip_prefix("/forget ") {
+            let removed = rag_store
+                .as_mut()
+                .map_or(0, |store| store.remove_document(prefix.trim()));
+            println!("Removed {} chunk(s).", removed);
+            continue;
         }
 
-        // update history
-        conversation_history.push_str(&format!(
-            "\n\n{}: {}",
-            agent.persona.name,
-            full_response.trim()
-        ));
+        if let Some(interval) = persona.context_refresh_interval_secs
+            && let Some(store) = &mut rag_store
+            && last_refresh.elapsed() >= Duration::from_secs(interval)
+        {
+            store.refresh().await?;
+            last_refresh = Instant::now();
+        }
+
+        let context_str = if let Some(store) = &rag_store {
+            let context_chunks: Vec<String> = store
+                .search(input, args.rag_chunks)
+                .await?
+                .iter()
+                .map(context_snippet)
+                .collect();
+            if !context_chunks.is_empty() {
+                format!(
+                    "Here is some relevant context from the local files:\n\n{}\n",
+                    context_chunks.join("\n")
+                )
+            } else {
+                String::new()
+            }
+        } else {
+            String::new()
+        };
+
+        // system prompt goes in front of the first user message
+        let system_prompt = if history.is_empty() {
+            format!("{}\n\n", persona.system_prompt)
+        } else {
+            String::new()
+        };
+        let mut messages = history.clone();
+        messages.push(Message {
+            role: "user".to_string(),
+            content: format!(
+                "{}{}\n\nUser question: {}",
+                system_prompt, context_str, input
+            ),
+        });
+
+        let full_response = print_stream(model.as_ref(), &messages, &gen_config).await?;
+        println!();
+
+        history.push(Message {
+            role: "user".to_string(),
+            content: format!("{}User question: {}", system_prompt, input),
+        });
+        history.push(Message {
+            role: "assistant".to_string(),
+            content: full_response,
+        });
+    }
+
+    Ok(())
+}
+
+async fn run_benchmark(args: BenchmarkArgs, verbose: bool) -> Result<()> {
+    let persona = config::load_persona(&args.persona)?;
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let model = build_model(&persona, &api_key, verbose)?;
+    let gen_config = generation_config(&persona);
+
+    let prompts: Vec<String> = std::fs::read_to_string(&args.prompt_file)
+        .map_err(|e| anyhow!("Failed to read prompt file {:?}: {}", args.prompt_file, e))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    if prompts.is_empty() {
+        return Err(anyhow!("No prompts found in {:?}", args.prompt_file));
+    }
+
+    let total_requests = prompts.len() * args.iterations;
+    println!(
+        "Benchmarking '{}' (Model: {}) with {} requests...",
+        persona.name, persona.model, total_requests
+    );
+
+    let mut latencies = Vec::with_capacity(total_requests);
+    let mut total_tokens = 0;
+    for iteration in 0..args.iterations {
+        for (i, prompt) in prompts.iter().enumerate() {
+            let messages = vec![Message {
+                role: "user".to_string(),
+                content: format!("{}\n\nUser question: {}", persona.system_prompt, prompt),
+            }];
+            let start = Instant::now();
+            let response = model
+                .ask(&messages, &gen_config)
+                .await
+                .map_err(|e| anyhow!(e))?;
+            let elapsed = start.elapsed();
+            println!(
+                "[{}/{}] prompt {}: {:.0} ms",
+                iteration * prompts.len() + i + 1,
+                total_requests,
+                i + 1,
+                elapsed.as_secs_f64() * 1000.0
+            );
+            latencies.push(elapsed);
+            total_tokens += count_tokens(&response);
+        }
+    }
+
+    latencies.sort();
+    let percentile = |p: f64| percentile_ms(&latencies, p);
+    let total_secs: f64 = latencies.iter().map(Duration::as_secs_f64).sum();
+
+    println!("\n--- Benchmark Summary ---");
+    println!("{:<12} {:>12}", "requests", total_requests);
+    println!("{:<12} {:>9.0} ms", "min", percentile(0.0));
+    println!("{:<12} {:>9.0} ms", "p50", percentile(0.5));
+    println!("{:<12} {:>9.0} ms", "p95", percentile(0.95));
+    println!("{:<12} {:>9.0} ms", "max", percentile(1.0));
+    println!("{:<12} {:>12}", "~tokens", total_tokens);
+    println!(
+        "{:<12} {:>8.1} tok/s",
+        "throughput",
+        total_tokens as f64 / total_secs.max(f64::EPSILON)
+    );
+    Ok(())
+}
+
+async fn run_rag_explain(persona_name: &str, query: &str, top_k: usize) -> Result<()> {
+    let persona = config::load_persona(persona_name)?;
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let store = build_rag_store(&persona, &api_key, None, None)
+        .await?
+        .ok_or_else(|| anyhow!("Persona '{}' has no context to search", persona.name))?;
+
+    let explanations = store.explain_retrieval(query, top_k).await?;
+    println!(
+        "{:>5} {:>10}  {:<40} PREVIEW",
+        "RANK", "SIMILARITY", "SOURCE"
+    );
+    for explanation in &explanations {
+        println!(
+            "{:>4}{} {:>10.4}  {:<40} {}",
+            explanation.rank + 1,
+            if explanation.was_returned { "*" } else { " " },
+            explanation.similarity,
+            explanation.source,
+            explanation.text_preview
+        );
+    }
+    println!(
+        "\n* returned by a search for the top {} (before MMR or reranking)",
+        top_k
+    );
+    Ok(())
+}
+
+async fn run_rag_clusters(persona_name: &str, num_clusters: usize, verbose: bool) -> Result<()> {
+    let persona = config::load_persona(persona_name)?;
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let model = build_model(&persona, &api_key, verbose)?;
+    let store = build_rag_store(&persona, &api_key, None, None)
+        .await?
+        .ok_or_else(|| anyhow!("Persona '{}' has no context to cluster", persona.name))?;
+
+    let clusters = store.cluster_chunks(num_clusters, model.as_ref()).await?;
+    for (i, cluster) in clusters.iter().enumerate() {
+        println!(
+            "\n--- Cluster {} ({} chunks) ---\n{}",
+            i + 1,
+            cluster.chunks.len(),
+            cluster.centroid_label
+        );
+        for chunk in &cluster.chunks {
+            println!("  {}", chunk);
+        }
+    }
+    Ok(())
+}
+
+async fn run_rag_stats(persona_name: &str, json: bool) -> Result<()> {
+    let persona = config::load_persona(persona_name)?;
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let store = build_rag_store(&persona, &api_key, None, None)
+        .await?
+        .ok_or_else(|| anyhow!("Persona '{}' has no context to index", persona.name))?;
+
+    let stats = store.stats();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    println!("\n--- RAG Index: {} ---", persona.name);
+    println!("{:<22} {:>12}", "chunks", stats.num_chunks);
+    println!("{:<22} {:>12}", "sources", stats.num_sources);
+    println!("{:<22} {:>12}", "text bytes", stats.total_text_bytes);
+    println!(
+        "{:<22} {:>12}",
+        "embedding dimensions", stats.embedding_dimensions
+    );
+    println!(
+        "{:<22} {:>9.1} MB",
+        "embedding memory",
+        stats.approx_memory_bytes as f64 / 1e6
+    );
+    println!("\nSources:");
+    for source in &stats.unique_sources {
+        println!("  {}", source);
+    }
+    Ok(())
+}
+
+async fn run_embed(text: &str, format: EmbedFormat) -> Result<()> {
+    let api_key = error::api_key("GEMINI_API_KEY")?;
+    let embedding = rag::embed_text(&api_key, text).await?;
+    match format {
+        EmbedFormat::Json => println!("{}", serde_json::to_string(&embedding)?),
+        EmbedFormat::Npy => {
+            let mut stdout = io::stdout();
+            stdout.write_all(&npy_bytes(&embedding))?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// A 1-d float32 array in NumPy's .npy format (version 1.0).
+fn npy_bytes(values: &[f32]) -> Vec<u8> {
+    const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}",
+        values.len()
+    );
+    // magic, the u16 header length and the header are padded to a multiple of 64 bytes
+    let unpadded = MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    for value in values {
+        bytes.extend(value.to_le_bytes());
+    }
+    bytes
+}
+
+async fn run_models(vendor: &str) -> Result<()> {
+    let model: Box<dyn LanguageModel> = match vendor {
+        "gemini" => {
+            let api_key = error::api_key("GEMINI_API_KEY")?;
+            Box::new(Gemini::new(api_key, gemini::DEFAULT_MODEL.to_string()))
+        }
+        other => {
+            return Err(anyhow!(
+                "Listing models is not supported for vendor '{}'",
+                other
+            ));
+        }
+    };
+
+    let mut models = model
+        .list_models()
+        .await
+        .map_err(|e| anyhow!("Failed to list models: {}", e))?;
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let limit = |l: Option<u32>| l.map_or_else(|| "-".to_string(), |l| l.to_string());
+    println!(
+        "{:<40} {:<40} {:>10} {:>10}",
+        "ID", "NAME", "INPUT", "OUTPUT"
+    );
+    for m in &models {
+        println!(
+            "{:<40} {:<40} {:>10} {:>10}",
+            m.id,
+            m.display_name,
+            limit(m.input_token_limit),
+            limit(m.output_token_limit)
+        );
+    }
+    Ok(())
+}
+
+// The persona named on the command line, or else the default from config.toml.
+fn persona_or_default(persona: Option<&str>) -> Result<String> {
+    if let Some(name) = persona {
+        return Ok(name.to_string());
+    }
+    match config::load_global_config()?.default_persona {
+        Some(name) => {
+            println!("Using default persona: {}", name);
+            Ok(name)
+        }
+        None => Err(CliError::InvalidArgument(
+            "No persona given, pass one with -p or set a default with `aiterm persona set-default <name>`"
+                .to_string(),
+        )
+        .into()),
+    }
+}
+
+fn run_persona_validate(name: &str) -> Result<()> {
+    let findings = config::validate_persona(name)?;
+    if findings.is_empty() {
+        println!("Persona '{}' is valid.", name);
+        return Ok(());
+    }
+    println!("Persona '{}' has {} issue(s):", name, findings.len());
+    for (i, finding) in findings.iter().enumerate() {
+        println!("  {}. {}", i + 1, finding);
+    }
+    std::process::exit(1);
+}
+
+fn run_init() -> Result<()> {
+    let personas_dir = config::get_personas_dir()?;
+    let written = config::write_example_personas()?;
+
+    println!("Personas directory: {}", personas_dir.display());
+    if written.is_empty() {
+        println!("Example personas already exist, nothing to write.");
+    } else {
+        for name in &written {
+            println!("  created {}.toml", name);
+        }
     }
 
-    println!("\n\n--- Conversation Finished ---");
+    if env::var("GEMINI_API_KEY").is_err() {
+        println!("\nWarning: GEMINI_API_KEY is not set. Get a key from Google AI Studio and run:");
+        println!("  export GEMINI_API_KEY=<your key>");
+    }
+
+    println!("\n--- Getting started ---");
+    println!("Example personas: default, coder");
+    println!("  aiterm ask -p default \"What is the capital of France?\"");
+    println!("  aiterm ask -p coder --stream \"Write a binary search in Rust\"");
+    println!("Add your own personas as .toml files in the directory above.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vendors::mock::MockModel;
+
+    fn persona(name: &str) -> Persona {
+        toml::from_str(&format!(
+            "name = \"{name}\"\nmodel = \"mock\"\nsystem_prompt = \"You are {name}.\"\n"
+        ))
+        .unwrap()
+    }
+
+    fn agent(name: &str, model: MockModel) -> Agent {
+        Agent {
+            persona: persona(name),
+            model: Box::new(model),
+            rag_store: None,
+        }
+    }
+
+    fn parse(args: &[&str]) -> Commands {
+        Cli::try_parse_from(std::iter::once("aiterm").chain(args.iter().copied()))
+            .unwrap()
+            .command
+    }
+
+    fn ask_args(args: &[&str]) -> AskArgs {
+        match parse(&[&["ask", "-p", "test"], args].concat()) {
+            Commands::Ask(args) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    fn converse_args(args: &[&str]) -> ConverseArgs {
+        match parse(&[&["converse"], args].concat()) {
+            Commands::Converse(args) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("aiterm-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn ask_returns_response_and_appends_to_session() {
+        let session = temp_path("session.json");
+        let session_arg = session.to_str().unwrap();
+        let persona = persona("test");
+
+        let model = MockModel::new(["Paris"]);
+        let args = ask_args(&["--session-file", session_arg, "Capital of France?"]);
+        let response = ask(&args, false, &persona, &model, &[], Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(response, "Paris");
+
+        let model = MockModel::new(["Berlin"]);
+        let args = ask_args(&["--stream", "--session-file", session_arg, "And Germany?"]);
+        let response = ask(&args, false, &persona, &model, &[], Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(response, "Berlin");
+
+        let history = session::load(&session).unwrap();
+        let contents: Vec<(&str, &str)> = history
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                ("user", "User question: Capital of France?"),
+                ("assistant", "Paris"),
+                ("user", "User question: And Germany?"),
+                ("assistant", "Berlin"),
+            ]
+        );
+        std::fs::remove_file(&session).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ask_collects_num_responses() {
+        let persona = persona("test");
+        let model = MockModel::new(["one", "two", "three"]);
+        let args = ask_args(&["--num-responses", "3", "Name a number"]);
+        let response = ask(&args, false, &persona, &model, &[], Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(response, "one\n\ntwo\n\nthree");
+    }
+
+    #[tokio::test]
+    async fn ask_retries_empty_response_with_rephrased_prompt() {
+        let persona = persona("test");
+        let model = MockModel::new(["", "  ", "an answer"]);
+        let args = ask_args(&[
+            "--retry-on-refusal",
+            "--max-refusal-retries",
+            "2",
+            "How do locks work?",
+        ]);
+        let response = ask(&args, false, &persona, &model, &[], Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(response, "an answer");
+
+        let requests = model.requests();
+        assert_eq!(requests.len(), 3);
+        let first = &requests[0].last().unwrap().content;
+        let retried = &requests[1].last().unwrap().content;
+        assert!(!first.contains(REFUSAL_REPHRASE_INSTRUCTION));
+        assert!(retried.starts_with(REFUSAL_REPHRASE_INSTRUCTION));
+        assert!(retried.contains("How do locks work?"));
+    }
+
+    #[test]
+    fn saving_to_a_knowledge_base_requires_the_api_key() {
+        let args = ["aiterm", "ask", "-p", "test", "--no-api-key-check"];
+        let parsed =
+            Cli::try_parse_from(
+                args.into_iter()
+                    .chain(["--save-to-knowledge-base", "kb", "q"]),
+            );
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn image_vendor_is_checked_after_the_model_override() {
+        let checked = |args: &[&str]| {
+            let args = ask_args(&[&["--image", "photo.png"], args, &["Describe"]].concat());
+            let mut persona = persona("test");
+            apply_model_override(&args, &mut persona);
+            check_image_vendor(&args, &persona)
+        };
+        assert!(checked(&[]).is_err());
+        assert!(checked(&["--model-override", "gemini/gemini-2.0-flash"]).is_ok());
+        assert!(checked(&["--model-override", "groq/llama-3.3-70b-versatile"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn rewrite_prompt_strips_quotes() {
+        let model = MockModel::new(["\"What is the capital of France?\"\n"]);
+        let rewritten = rewrite_prompt(&model, &GenerationConfig::default(), "france capital??")
+            .await
+            .unwrap();
+        assert_eq!(rewritten, "What is the capital of France?");
+
+        let model = MockModel::new(["  "]);
+        assert!(
+            rewrite_prompt(&model, &GenerationConfig::default(), "x")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn npy_header_is_aligned() {
+        let bytes = npy_bytes(&[1.0, -0.5]);
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert!(String::from_utf8_lossy(&bytes[10..10 + header_len]).contains("'shape': (2,)"));
+        assert_eq!(
+            &bytes[10 + header_len..],
+            [1.0f32.to_le_bytes(), (-0.5f32).to_le_bytes()].concat()
+        );
+    }
+
+    #[test]
+    fn images_are_base64_with_mime_type_from_extension() {
+        let file = temp_path("pixel.PNG");
+        std::fs::write(&file, b"\x89PNG").unwrap();
+        let image = load_image(&file).unwrap();
+        assert_eq!(image.mime_type, "image/png");
+        assert_eq!(image.data, "iVBORw==");
+        std::fs::remove_file(&file).unwrap();
+
+        assert!(load_image(Path::new("photo.gif")).is_err());
+    }
+
+    #[test]
+    fn ad_hoc_persona_context_paths_are_relative_to_its_file() {
+        let dir = temp_path("adhoc");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("reviewer.toml");
+        std::fs::write(
+            &file,
+            "name = \"reviewer\"\nmodel = \"mock\"\nsystem_prompt = \"Review.\"\ncontext_paths = [\"docs/*.md\", \"/etc/hosts\"]\n",
+        )
+        .unwrap();
+        let persona = config::load_persona_file(&file).unwrap();
+        assert_eq!(
+            persona.context_paths,
+            [dir.join("docs/*.md").to_string_lossy(), "/etc/hosts".into()]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_allowed_tools_are_offered() {
+        assert!(offered_tools(&persona("plain")).is_empty());
+
+        let mut scoped = persona("scoped");
+        scoped.allowed_tools = vec!["read_file".to_string(), "run_shell".to_string()];
+        let names: Vec<String> = offered_tools(&scoped)
+            .into_iter()
+            .map(|tool| tool.name)
+            .collect();
+        assert_eq!(names, ["read_file"]);
+    }
+
+    #[test]
+    fn latency_percentiles_use_nearest_rank() {
+        let latencies: Vec<Duration> = (1..=20).map(|ms| Duration::from_millis(ms * 10)).collect();
+        assert_eq!(percentile_ms(&latencies, 0.0), 10.0);
+        assert_eq!(percentile_ms(&latencies, 0.5), 100.0);
+        assert_eq!(percentile_ms(&latencies, 0.95), 190.0);
+        assert_eq!(percentile_ms(&latencies, 1.0), 200.0);
+        assert_eq!(percentile_ms(&latencies[..1], 0.95), 10.0);
+    }
+
+    #[test]
+    fn ask_fills_in_prompt_template() {
+        let mut persona = persona("test");
+        persona.prompt_templates.insert(
+            "review".to_string(),
+            "Review this code:\n{prompt}".to_string(),
+        );
+
+        let args = ask_args(&["--template", "review", "fn", "main()"]);
+        assert_eq!(
+            ask_prompt(&args, &persona).unwrap(),
+            "Review this code:\nfn main()"
+        );
+        let args = ask_args(&["--template", "missing", "fn main()"]);
+        assert!(ask_prompt(&args, &persona).is_err());
+    }
+
+    #[tokio::test]
+    async fn ask_keeps_a_sliding_session_window() {
+        let session = temp_path("window.json");
+        let session_arg = session.to_str().unwrap();
+        let persona = persona("test");
+
+        for (question, answer) in [("one?", "1"), ("two?", "2")] {
+            let model = MockModel::new([answer]);
+            let args = ask_args(&[
+                "--session",
+                session_arg,
+                "--max-session-messages",
+                "3",
+                question,
+            ]);
+            ask(&args, false, &persona, &model, &[], Vec::new())
+                .await
+                .unwrap();
+        }
+
+        let history = session::load(&session).unwrap();
+        let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+        // the third slot would hold "1", a reply without its question
+        assert_eq!(contents, ["User question: two?", "2"]);
+        assert_eq!(history[0].role, "user");
+        std::fs::remove_file(&session).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ask_pipes_response_to_command() {
+        let output = temp_path("piped.txt");
+        let command = format!("cat > {}", output.display());
+        let persona = persona("test");
+
+        for (stream_flag, response) in [("--no-rag", "buffered"), ("--stream", "streamed")] {
+            let model = MockModel::new([response]);
+            let args = ask_args(&["--pipe-to", &command, stream_flag, "Say something"]);
+            ask(&args, false, &persona, &model, &[], Vec::new())
+                .await
+                .unwrap();
+            assert_eq!(std::fs::read_to_string(&output).unwrap(), response);
+        }
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[tokio::test]
+    async fn max_prompt_tokens_drops_the_least_similar_chunk_of_any_store() {
+        // every text is in the cache, so nothing is sent to the embedding API
+        let cache = EmbeddingCache::shared(16);
+        let word = |w: &str| vec![w; 200].join(" ");
+        let embeddings = [
+            ("weak", vec![0.6, 0.8]),
+            ("strong", vec![1.0, 0.1]),
+            ("Question", vec![1.0, 0.0]),
+        ];
+        for (text, embedding) in embeddings {
+            let text = if text == "Question" {
+                text.to_string()
+            } else {
+                word(text)
+            };
+            cache
+                .lock()
+                .unwrap()
+                .put(rag::DEFAULT_EMBED_MODEL, &text, embedding);
+        }
+        let dir = temp_path("two-stores");
+        let mut stores = Vec::new();
+        for name in ["weak", "strong"] {
+            let file = dir.join(name).join(format!("{}.rs", name));
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(&file, word(name)).unwrap();
+            let store = RagStore::builder("unused".to_string(), &[file.display().to_string()])
+                .cache(Arc::clone(&cache))
+                .build()
+                .await
+                .unwrap();
+            stores.push(RwLock::new(store));
+        }
+
+        let mut persona = persona("test");
+        // room for two of the three ~250 token snippets
+        persona.max_prompt_tokens = Some(650);
+        let model = MockModel::new(["ok"]);
+        let args = ask_args(&["Question"]);
+        let stores: Vec<&RwLock<RagStore>> = stores.iter().collect();
+        ask(&args, false, &persona, &model, &stores, vec![word("raw")])
+            .await
+            .unwrap();
+
+        let requests = model.requests();
+        let sent = &requests[0].last().unwrap().content;
+        assert!(sent.contains("strong strong"));
+        assert!(sent.contains("raw raw"));
+        assert!(!sent.contains("weak weak"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ask_with_empty_rag_context() {
+        // no files to chunk, so neither indexing nor search touch the embedding API
+        let dir = temp_path("empty-context");
+        std::fs::create_dir(&dir).unwrap();
+        let paths = vec![dir.to_str().unwrap().to_string()];
+        let store = RagStore::builder("unused".to_string(), &paths)
+            .build()
+            .await
+            .unwrap();
+        let store = RwLock::new(store);
+
+        let model = MockModel::new(["No context needed."]);
+        let args = ask_args(&["Hello"]);
+        let response = ask(
+            &args,
+            false,
+            &persona("test"),
+            &model,
+            &[&store],
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response, "No context needed.");
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn converse_takes_turns_round_robin() {
+        let agents = [
+            agent("alice", MockModel::new(["a1", "a2"])),
+            agent("bob", MockModel::new(["b1 "])),
+        ];
+        let observer = agent("observer", MockModel::new(["summary"]));
+        let args = converse_args(&[
+            "--persona",
+            "alice",
+            "bob",
+            "--turns",
+            "3",
+            "--observer",
+            "observer",
+            "--",
+            "Discuss",
+        ]);
+
+        let transcript = converse(&args, &agents, Some(&observer), None)
+            .await
+            .unwrap();
+        let turns: Vec<(&str, &str)> = transcript
+            .conversation
+            .iter()
+            .map(|t| (t.agent.as_str(), t.response.as_str()))
+            .collect();
+        assert_eq!(turns, [("alice", "a1"), ("bob", "b1"), ("alice", "a2")]);
+        assert_eq!(transcript.initial_prompt, "Discuss");
+    }
+
+    #[tokio::test]
+    async fn converse_follows_agent_weights() {
+        let agents = [
+            agent("author", MockModel::new(["a1", "a2"])),
+            agent("critic", MockModel::new(["c1", "c2", "c3", "c4"])),
+        ];
+        let args = converse_args(&[
+            "--persona",
+            "author",
+            "critic",
+            "--turns",
+            "5",
+            "--agent-weights",
+            "critic=3,author=1",
+            "--",
+            "Review the draft",
+        ]);
+
+        let transcript = converse(&args, &agents, None, None).await.unwrap();
+        let speakers: Vec<&str> = transcript
+            .conversation
+            .iter()
+            .map(|t| t.agent.as_str())
+            .collect();
+        assert_eq!(speakers, ["critic", "author", "critic", "critic", "critic"]);
+
+        let args = converse_args(&[
+            "--persona",
+            "author",
+            "critic",
+            "--agent-weights",
+            "critic=3",
+            "--",
+            "x",
+        ]);
+        assert!(converse(&args, &agents, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn converse_writes_jsonl_transcript() {
+        let file = temp_path("turns.jsonl");
+        let agents = [
+            agent("alice", MockModel::new(["a1"])),
+            agent("bob", MockModel::new(["b1"])),
+        ];
+        let observer = agent("observer", MockModel::new(["summary"]));
+        let args = converse_args(&[
+            "--persona",
+            "alice",
+            "bob",
+            "--turns",
+            "2",
+            "--observer",
+            "observer",
+            "--transcript-format",
+            "jsonl",
+            "--transcript-file",
+            file.to_str().unwrap(),
+            "--",
+            "Discuss",
+        ]);
+
+        converse(&args, &agents, Some(&observer), None)
+            .await
+            .unwrap();
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let turns: Vec<(&serde_json::Value, &str, &str)> = lines
+            .iter()
+            .map(|l| {
+                (
+                    &l["turn"],
+                    l["agent"].as_str().unwrap(),
+                    l["response"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            turns,
+            [
+                (&serde_json::json!(1), "alice", "a1"),
+                (&serde_json::json!(2), "bob", "b1"),
+                (&serde_json::Value::Null, "observer", "summary"),
+            ]
+        );
+        assert!(lines[0]["timestamp"].is_string());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn seed_file_replaces_intro_with_prior_turns() {
+        let file = temp_path("seed.jsonl");
+        std::fs::write(
+            &file,
+            [
+                r#"{"turn":1,"agent":"alice","timestamp":"t","response":"a1"}"#,
+                "",
+                r#"{"turn":2,"agent":"bob","timestamp":"t","response":"b1\nmore"}"#,
+                r#"{"turn":null,"agent":"observer","timestamp":"t","response":"summary"}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let records = read_turn_records(&file).unwrap();
+        assert_eq!(
+            seeded_intro(&records, ""),
+            "This conversation continues an earlier one:\n\nalice: a1\n\nbob: b1\nmore"
+        );
+        assert!(seeded_intro(&records, "Go on").ends_with(
+            "bob: b1\nmore\n\nThe user continued the conversation with this prompt: \"Go on\""
+        ));
+        assert!(
+            converse_args(&["-p", "alice", "bob", "--seed-file", "x"])
+                .prompt
+                .is_empty()
+        );
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn converse_uses_injected_turns() {
+        let agents = [
+            agent("alice", MockModel::new(["a1"])),
+            agent("bob", MockModel::new(["b1"])),
+        ];
+        let args = converse_args(&[
+            "--persona",
+            "alice",
+            "bob",
+            "--turns",
+            "3",
+            "--inject-turn",
+            "2:Alice:Let's talk about: cats",
+            "--",
+            "Discuss",
+        ]);
+
+        let transcript = converse(&args, &agents, None, None).await.unwrap();
+        let turns: Vec<(&str, &str)> = transcript
+            .conversation
+            .iter()
+            .map(|t| (t.agent.as_str(), t.response.as_str()))
+            .collect();
+        assert_eq!(
+            turns,
+            [
+                ("alice", "a1"),
+                ("alice", "Let's talk about: cats"),
+                ("bob", "b1")
+            ]
+        );
+
+        let args = converse_args(&["-p", "alice", "bob", "--inject-turn", "9:bob:hi", "--", "x"]);
+        assert!(converse(&args, &agents, None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn converse_directed_follows_mentions() {
+        let agents = [
+            agent("alice", MockModel::new(["a1\n@carol", "a2\n@nobody"])),
+            agent("bob", MockModel::new(["b1"])),
+            agent("carol", MockModel::new(["c1\n@Alice."])),
+        ];
+        let args = converse_args(&[
+            "--persona",
+            "alice",
+            "bob",
+            "carol",
+            "--turns",
+            "4",
+            "--directed",
+            "--",
+            "Go",
+        ]);
+
+        let transcript = converse(&args, &agents, None, None).await.unwrap();
+        let agents: Vec<&str> = transcript
+            .conversation
+            .iter()
+            .map(|t| t.agent.as_str())
+            .collect();
+        // an unknown mention falls back to round-robin
+        assert_eq!(agents, ["alice", "carol", "alice", "bob"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn converse_skips_timed_out_turns() {
+        let agents = [
+            agent("fast", MockModel::new(["f1", "f2"])),
+            agent(
+                "slow",
+                MockModel::new(["s1"]).with_latency(Duration::from_secs(5)),
+            ),
+        ];
+        let args = converse_args(&[
+            "--persona",
+            "fast",
+            "slow",
+            "--turns",
+            "3",
+            "--turns-timeout",
+            "1",
+            "--",
+            "Go",
+        ]);
+
+        let transcript = converse(&args, &agents, None, None).await.unwrap();
+        let agents: Vec<&str> = transcript
+            .conversation
+            .iter()
+            .map(|t| t.agent.as_str())
+            .collect();
+        assert_eq!(agents, ["fast", "fast"]);
+    }
+
+    #[test]
+    fn history_truncation_drops_oldest_turns_but_keeps_prompt() {
+        let mut history = ConversationHistory::new("prompt".to_string());
+        for turn in 1..=4 {
+            history.push(turn, format!("\n\nturn {turn} {}", "x".repeat(40)));
+        }
+        let intro_and_last_two = count_tokens("prompt")
+            + history.turns[2..]
+                .iter()
+                .map(|(_, entry)| count_tokens(entry))
+                .sum::<usize>();
+
+        history.truncate(intro_and_last_two);
+        let turns: Vec<usize> = history.turns.iter().map(|(turn, _)| *turn).collect();
+        assert_eq!(turns, [3, 4]);
+        assert!(history.render().starts_with("prompt"));
+
+        history.truncate(0);
+        assert_eq!(history.render(), "prompt");
+    }
+
+    #[tokio::test]
+    async fn history_summary_replaces_oldest_half() {
+        let mut history = ConversationHistory::new("prompt".to_string());
+        for turn in 1..=3 {
+            history.push(turn, format!("\n\nagent: turn {turn}"));
+        }
+        let model = MockModel::new(["they agreed "]);
+
+        let summarized = history
+            .summarize_oldest(&model, &GenerationConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(summarized, Some((1, 2)));
+        assert_eq!(
+            history.render(),
+            "prompt\n\n[Summary of turns 1-2: they agreed]\n\nagent: turn 3"
+        );
+    }
+
+    #[test]
+    fn edit_in_editor_returns_saved_text() {
+        let edited = edit_in_editor("sed -i s/hello/goodbye/", "hello world").unwrap();
+        assert_eq!(edited, "goodbye world");
+    }
+
+    #[test]
+    fn exit_codes_follow_error_kind() {
+        let rate_limited = VendorError::ApiError {
+            status: 429,
+            message: String::new(),
+        };
+        assert_eq!(error::exit_code(&rate_limited.into()), 4);
+        let during_index = anyhow::Error::from(VendorError::ApiError {
+            status: 401,
+            message: String::new(),
+        })
+        .context(CliError::RagIndexFailed);
+        assert_eq!(error::exit_code(&during_index), 5);
+        let embedding_rate_limited = anyhow::Error::from(rag::EmbeddingApiError {
+            status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+            message: String::new(),
+        })
+        .context(CliError::RagIndexFailed);
+        assert_eq!(error::exit_code(&embedding_rate_limited), 4);
+        let index_failed = anyhow!("no files").context(CliError::RagIndexFailed);
+        assert_eq!(error::exit_code(&index_failed), 6);
+        let missing = CliError::PersonaNotFound(PathBuf::from("x.toml"));
+        assert_eq!(error::exit_code(&missing.into()), 2);
+        assert_eq!(error::exit_code(&CliError::PipeFailed(3).into()), 3);
+        assert_eq!(error::exit_code(&anyhow!("anything else")), 1);
+    }
+
+    #[test]
+    fn fallback_only_after_rate_limit_or_unavailable() {
+        let api_error = |status| {
+            anyhow::Error::from(VendorError::ApiError {
+                status,
+                message: String::new(),
+            })
+        };
+        assert_eq!(fallback_reason(&api_error(429)), Some("rate limit"));
+        assert_eq!(
+            fallback_reason(&api_error(503)),
+            Some("service unavailable")
+        );
+        assert_eq!(fallback_reason(&api_error(401)), None);
+        assert_eq!(fallback_reason(&anyhow!("no network")), None);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "more times than it has canned responses")]
+    async fn mock_model_panics_when_out_of_responses() {
+        let model = MockModel::new(["only one"]);
+        let config = GenerationConfig::default();
+        model.ask(&[], &config).await.unwrap();
+        let _ = model.ask(&[], &config).await;
+    }
+}
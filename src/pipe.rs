@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use std::io::{ErrorKind, Read, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
+
+// A shell command fed the response on stdin, for `ask --pipe-to`. Its output is
+// collected on background threads so a chatty command can't block our writes.
+pub struct Pipe {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: JoinHandle<Vec<u8>>,
+    stderr: JoinHandle<Vec<u8>>,
+}
+
+impl Pipe {
+    pub fn spawn(command: &str) -> Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run `{}`", command))?;
+        let stdin = child.stdin.take();
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+
+    // A command that stops reading early (e.g. `head`) just doesn't get the rest.
+    pub fn write(&mut self, text: &str) -> Result<()> {
+        if let Some(stdin) = &mut self.stdin {
+            match stdin.write_all(text.as_bytes()) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => self.stdin = None,
+                Err(e) => return Err(e).context("Failed to write to --pipe-to command"),
+            }
+        }
+        Ok(())
+    }
+
+    // Closes stdin, waits for the command and prints what it wrote.
+    pub fn finish(mut self) -> Result<ExitStatus> {
+        drop(self.stdin.take());
+        let status = self.child.wait()?;
+        let stdout = self.stdout.join().unwrap_or_default();
+        let stderr = self.stderr.join().unwrap_or_default();
+        if !stdout.is_empty() {
+            println!(
+                "\n--- Command output ---\n{}",
+                String::from_utf8_lossy(&stdout)
+            );
+        }
+        if !stderr.is_empty() {
+            eprintln!("{}", String::from_utf8_lossy(&stderr));
+        }
+        Ok(status)
+    }
+}
+
+fn read_in_background(source: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut source) = source {
+            let _ = source.read_to_end(&mut output);
+        }
+        output
+    })
+}
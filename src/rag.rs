@@ -1,8 +1,30 @@
 // its all into todo
+use crate::vendors::{GenerationConfig, LanguageModel, Message, http_client};
 use anyhow::{Context, Result};
+use lru::LruCache;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use walkdir::WalkDir;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Read};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{RwLock, mpsc};
+use tokio::task::JoinHandle;
+
+mod index;
+// with compressed-embeddings only the f16 version is used
+#[cfg_attr(feature = "compressed-embeddings", allow(dead_code))]
+mod similarity;
+mod web;
+#[cfg(not(feature = "compressed-embeddings"))]
+use similarity::cos_sim;
+#[cfg(feature = "compressed-embeddings")]
+use similarity::cos_sim_f16;
 
 // some Structures
 #[derive(Serialize)]
@@ -19,10 +41,6 @@ struct Part {
     text: String,
 }
 
-#[derive(Deserialize)]
-struct EmbeddingResponse {
-    embedding: EmbeddingObject,
-}
 #[derive(Deserialize)]
 struct EmbeddingObject {
     values: Vec<f32>,
@@ -33,157 +51,1906 @@ struct BatchEmbeddingResponse {
     embeddings: Vec<EmbeddingObject>,
 }
 
+// Together AI's OpenAI-compatible /v1/embeddings
+#[derive(Serialize)]
+struct TogetherEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+#[derive(Deserialize)]
+struct TogetherEmbeddingResponse {
+    data: Vec<TogetherEmbedding>,
+}
+#[derive(Deserialize)]
+struct TogetherEmbedding {
+    embedding: Vec<f32>,
+    // position in `input`
+    index: usize,
+}
+
+#[derive(Serialize)]
+struct RerankRequest<'a> {
+    model: &'a str,
+    query: &'a str,
+    documents: &'a [&'a str],
+    top_n: usize,
+}
+#[derive(Deserialize)]
+struct RerankResponse {
+    results: Vec<RerankResult>,
+}
+#[derive(Deserialize)]
+struct RerankResult {
+    index: usize,
+}
+
 // Represents a piece of text from a file.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct TextChunk {
     source: String,
     text: String,
+    start_line: usize,
+    end_line: usize,
+}
+
+// A chunk returned by `RagStore::search`.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    // file (or URL) and line range
+    pub source: String,
+    pub text: String,
+    // cosine similarity to the query, the best over all variants with query expansion
+    pub similarity: f32,
+    // position of the chunk in the store
+    pub chunk_index: usize,
+}
+
+// One chunk's standing for a query, see `RagStore::explain_retrieval`.
+#[derive(Debug)]
+pub struct RetrievalExplanation {
+    // 0 is the most similar chunk
+    pub rank: usize,
+    // file (or URL) and line range
+    pub source: String,
+    pub similarity: f32,
+    pub text_preview: String,
+    pub was_returned: bool,
+}
+
+// A group of similar chunks, see `RagStore::cluster_chunks`.
+#[derive(Debug)]
+pub struct ChunkCluster {
+    // one sentence from the model describing the cluster's topic
+    pub centroid_label: String,
+    // file (or URL) and line range of each member, closest to the centroid first
+    pub chunks: Vec<String>,
+}
+
+// Size of a store's index, see `RagStore::stats`.
+#[derive(Debug, Serialize)]
+pub struct RagStoreStats {
+    pub num_chunks: usize,
+    pub num_sources: usize,
+    // sorted
+    pub unique_sources: Vec<String>,
+    pub total_text_bytes: usize,
+    // 0 for an empty store
+    pub embedding_dimensions: usize,
+    // of the embeddings alone, 4 bytes per value (2 with compressed-embeddings)
+    pub approx_memory_bytes: usize,
 }
 
 // main store
 pub struct RagStore {
     api_key: String,
     client: reqwest::Client,
+    paths: Vec<String>,
+    embed_batch_size: usize,
+    chunking: ChunkOptions,
+    exclude_patterns: Vec<glob::Pattern>,
+    all_text_files: bool,
+    cache: Option<SharedEmbeddingCache>,
+    // `api_key` is this vendor's
+    embed_vendor: EmbedVendor,
+    // without the "models/" prefix
+    embed_model: String,
+    // modification time of every indexed file, to find the ones `refresh` must redo
+    mtimes: HashMap<String, SystemTime>,
     chunks: Vec<TextChunk>,
-    embeddings: Vec<Vec<f32>>,
+    embeddings: Vec<Embedding>,
+    mmr_lambda: f32,
+    cohere_api_key: Option<String>,
 }
 
-impl RagStore {
-    pub async fn new(api_key: String, paths: &[String]) -> Result<Self> {
+// Stored chunk embeddings. With `compressed-embeddings` each value is kept as the bits
+// of an f16, which halves memory (768 dims: 1.5 KiB instead of 3 KiB per chunk).
+// Cosine scores move by ~1e-5 at most, far below what changes a ranking.
+#[cfg(not(feature = "compressed-embeddings"))]
+type Embedding = Vec<f32>;
+#[cfg(feature = "compressed-embeddings")]
+type Embedding = Vec<u16>;
+
+// Gemini embedding model used unless a persona picks another
+pub const DEFAULT_EMBED_MODEL: &str = "text-embedding-004";
+// the same for personas with embed_vendor = "together"
+pub const DEFAULT_TOGETHER_EMBED_MODEL: &str = "BAAI/bge-base-en-v1.5";
+const RERANK_CANDIDATE_FACTOR: usize = 4;
+// rephrasings of the query searched alongside it, see `RagStore::search_with_expansion`
+pub const DEFAULT_QUERY_EXPANSIONS: usize = 3;
+const KMEANS_MAX_ITERATIONS: usize = 50;
+// chunks shown to the model when labelling a cluster
+const CLUSTER_LABEL_CHUNKS: usize = 3;
+pub const MAX_CHUNK_SIZE: usize = 2000;
+const CHUNK_OVERLAP: usize = 200;
+pub const DEFAULT_EMBED_BATCH_SIZE: usize = 100;
+// context files above this many bytes are skipped, unless the persona says otherwise
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1_000_000;
+pub const DEFAULT_EXCLUDE_PATTERNS: &[&str] =
+    &[".git/**", "target/**", "node_modules/**", "*.lock"];
+// limits for a `context_commands` entry, longer output is cut off
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_COMMAND_OUTPUT: usize = 100_000;
+const COMMAND_SOURCE_PREFIX: &str = "command: ";
+
+// A table whose rows are indexed, see `RagStoreBuilder::sqlite`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SqliteContextConfig {
+    pub path: String,
+    pub table: String,
+    pub text_column: String,
+    pub id_column: String,
+}
+
+// set by `--quiet`
+static HIDE_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+// Turns off the embedding progress bar for the rest of the process.
+pub fn hide_progress() {
+    HIDE_PROGRESS.store(true, Ordering::Relaxed);
+}
+
+// Embeddings keyed by the SHA-256 of the model and the embedded text, so identical chunks
+// (or queries) are only sent to the API once per session.
+pub struct EmbeddingCache {
+    entries: LruCache<String, Vec<f32>>,
+}
+
+pub type SharedEmbeddingCache = Arc<Mutex<EmbeddingCache>>;
+
+impl EmbeddingCache {
+    pub fn shared(capacity: usize) -> SharedEmbeddingCache {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Arc::new(Mutex::new(Self {
+            entries: LruCache::new(capacity),
+        }))
+    }
+
+    // stores with different models can share a cache
+    fn key(model: &str, text: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(model.as_bytes());
+        hasher.update([0]);
+        hasher.update(text.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn get(&mut self, model: &str, text: &str) -> Option<Vec<f32>> {
+        self.entries.get(&Self::key(model, text)).cloned()
+    }
+
+    // also lets tests stand in for the embedding API
+    pub fn put(&mut self, model: &str, text: &str, embedding: Vec<f32>) {
+        self.entries.put(Self::key(model, text), embedding);
+    }
+}
+
+pub struct RagStoreBuilder {
+    api_key: String,
+    paths: Vec<String>,
+    urls: Vec<String>,
+    commands: Vec<String>,
+    sqlite: Vec<SqliteContextConfig>,
+    embed_batch_size: usize,
+    chunking: ChunkOptions,
+    exclude_patterns: Vec<glob::Pattern>,
+    all_text_files: bool,
+    cache: Option<SharedEmbeddingCache>,
+    embed_vendor: EmbedVendor,
+    embed_model: String,
+    index_file: Option<PathBuf>,
+}
+
+// Which API embeds chunks and queries, a persona's `embed_vendor`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedVendor {
+    #[default]
+    Gemini,
+    // needs TOGETHER_API_KEY
+    Together,
+}
+
+impl EmbedVendor {
+    pub fn default_model(self) -> &'static str {
+        match self {
+            EmbedVendor::Gemini => DEFAULT_EMBED_MODEL,
+            EmbedVendor::Together => DEFAULT_TOGETHER_EMBED_MODEL,
+        }
+    }
+}
+
+impl RagStoreBuilder {
+    // max texts per embedding API request
+    pub fn embed_batch_size(mut self, embed_batch_size: usize) -> Self {
+        self.embed_batch_size = embed_batch_size;
+        self
+    }
+
+    // None picks a strategy per file type
+    pub fn chunk_strategy(mut self, chunk_strategy: Option<ChunkStrategy>) -> Self {
+        self.chunking.strategy = chunk_strategy;
+        self
+    }
+
+    // Chunk size and overlap in bytes by file extension (without the dot), files with
+    // other extensions use MAX_CHUNK_SIZE and CHUNK_OVERLAP.
+    pub fn chunk_sizes(
+        mut self,
+        size_by_extension: &HashMap<String, usize>,
+        overlap_by_extension: &HashMap<String, usize>,
+    ) -> Self {
+        let normalize = |map: &HashMap<String, usize>| {
+            map.iter()
+                .map(|(ext, value)| (ext.trim_start_matches('.').to_lowercase(), *value))
+                .collect()
+        };
+        self.chunking.size_by_extension = normalize(size_by_extension);
+        self.chunking.overlap_by_extension = normalize(overlap_by_extension);
+        self
+    }
+
+    // Skip context files larger than this many bytes, e.g. lock files or minified code.
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.chunking.max_file_size = max_file_size;
+        self
+    }
+
+    // Glob patterns for files to skip inside context directories, matched against the
+    // path relative to the directory. Replaces DEFAULT_EXCLUDE_PATTERNS.
+    pub fn exclude_patterns(mut self, patterns: &[String]) -> Self {
+        self.exclude_patterns = compile_exclude_patterns(patterns);
+        self
+    }
+
+    // Index every file in the context directories instead of only those that look like
+    // text. Files that aren't valid UTF-8 still produce no chunks.
+    pub fn all_text_files(mut self, all_text_files: bool) -> Self {
+        self.all_text_files = all_text_files;
+        self
+    }
+
+    // web pages to index alongside the files, fetched once at build time
+    pub fn urls(mut self, urls: &[String]) -> Self {
+        self.urls = urls.to_vec();
+        self
+    }
+
+    // Shell commands whose stdout is indexed alongside the files, e.g. "git log
+    // --oneline -50". They run at build time, again when starting from an index file.
+    pub fn commands(mut self, commands: &[String]) -> Self {
+        self.commands = commands.to_vec();
+        self
+    }
+
+    // SQLite tables to index alongside the files, one source per row. Like web pages
+    // they are read once at build time.
+    pub fn sqlite(mut self, tables: &[SqliteContextConfig]) -> Self {
+        self.sqlite = tables.to_vec();
+        self
+    }
+
+    // The builder's api_key must be for this vendor. Switches to the vendor's default
+    // model, so call it before `embed_model`.
+    pub fn embed_vendor(mut self, vendor: EmbedVendor) -> Self {
+        self.embed_vendor = vendor;
+        self.embed_model = vendor.default_model().to_string();
+        self
+    }
+
+    // Gemini embedding model, e.g. "embedding-001", DEFAULT_EMBED_MODEL when not set.
+    // An index file embedded with another model is embedded again.
+    pub fn embed_model(mut self, model: &str) -> Self {
+        self.embed_model = model.trim_start_matches("models/").to_string();
+        self
+    }
+
+    // share one embedding cache between several stores
+    pub fn cache(mut self, cache: SharedEmbeddingCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Start from this index file if it exists (re-indexing only files changed since),
+    // and save the store to it after building.
+    pub fn index_file(mut self, path: Option<PathBuf>) -> Self {
+        self.index_file = path;
+        self
+    }
+
+    pub async fn build(mut self) -> Result<RagStore> {
         println!("Initializing...");
-        let client = reqwest::Client::new();
-        let chunks = Self::load_and_chunk_files(paths)?;
-
-        if chunks.is_empty() {
-            println!("Warning: No text files found in context paths.");
-            return Ok(Self {
-                api_key,
-                client,
-                chunks,
-                embeddings: vec![],
-            });
+        let Some(index_file) = self.index_file.take() else {
+            return self.build_from_sources().await;
+        };
+        let store = if index_file.exists() {
+            let imported = RagStore::import(
+                self.api_key.clone(),
+                &index_file,
+                self.embed_vendor,
+                &self.embed_model,
+            )
+            .await?;
+            let commands = std::mem::take(&mut self.commands);
+            let mut store = self.finish(imported.chunks, imported.embeddings, imported.mtimes);
+            let mtimes = store.mtimes.clone();
+            store.rerun_commands(&commands).await?;
+            store.refresh().await?;
+            if store.mtimes == mtimes {
+                return Ok(store);
+            }
+            store
+        } else {
+            self.build_from_sources().await?
+        };
+        store.export(&index_file)?;
+        println!("Saved index to {}", index_file.display());
+        Ok(store)
+    }
+
+    async fn build_from_sources(self) -> Result<RagStore> {
+        let client = http_client();
+        let (mut chunks, mtimes) = RagStore::load_and_chunk_files(
+            &self.paths,
+            &self.commands,
+            &self.exclude_patterns,
+            self.all_text_files,
+            &self.chunking,
+        )
+        .await?;
+        if !self.urls.is_empty() {
+            println!("Fetching {} web page(s)...", self.urls.len());
+            chunks.extend(web::load_and_chunk_urls(&web::client(), &self.urls).await);
+        }
+        for table in &self.sqlite {
+            match read_sqlite_rows(table) {
+                Ok(rows) => {
+                    for (source, text) in rows {
+                        chunks.extend(self.chunking.chunk(Path::new(""), &source, &text));
+                    }
+                }
+                Err(e) => println!(
+                    "Warning: Skipping table {} in {}: {:#}",
+                    table.table, table.path, e
+                ),
+            }
         }
 
-        println!("Embedding {} text chunks via API...", chunks.len());
-        let documents: Vec<String> = chunks.iter().map(|c| c.text.clone()).collect();
-        let embeddings = embed_batch(&client, &api_key, documents).await?;
-        println!("Embedding complete.");
+        let embeddings = if chunks.is_empty() {
+            println!("Warning: No text found in context paths, URLs, commands or tables.");
+            vec![]
+        } else {
+            println!("Embedding {} text chunks via API...", chunks.len());
+            let embeddings = embed_chunks(
+                &client,
+                self.embed_vendor,
+                &self.api_key,
+                &self.embed_model,
+                &chunks,
+                self.embed_batch_size,
+                self.cache.as_ref(),
+            )
+            .await?;
+            println!("Embedding complete.");
+            embeddings
+        };
 
-        Ok(Self {
-            api_key,
-            client,
+        Ok(self.finish(chunks, embeddings, mtimes))
+    }
+
+    fn finish(
+        self,
+        chunks: Vec<TextChunk>,
+        embeddings: Vec<Embedding>,
+        mtimes: HashMap<String, SystemTime>,
+    ) -> RagStore {
+        RagStore {
+            api_key: self.api_key,
+            client: http_client(),
+            paths: self.paths,
+            embed_batch_size: self.embed_batch_size,
+            chunking: self.chunking,
+            exclude_patterns: self.exclude_patterns,
+            all_text_files: self.all_text_files,
+            cache: self.cache,
+            embed_vendor: self.embed_vendor,
+            embed_model: self.embed_model,
+            mtimes,
             chunks,
             embeddings,
+            mmr_lambda: 1.0,
+            cohere_api_key: None,
+        }
+    }
+}
+
+// How file contents are split into chunks. When not set explicitly, Markdown and
+// plain text use paragraphs and everything else uses fixed-size windows.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkStrategy {
+    Fixed,
+    Paragraphs,
+}
+
+impl RagStore {
+    pub fn builder(api_key: String, paths: &[String]) -> RagStoreBuilder {
+        RagStoreBuilder {
+            api_key,
+            paths: paths.to_vec(),
+            urls: Vec::new(),
+            commands: Vec::new(),
+            sqlite: Vec::new(),
+            embed_batch_size: DEFAULT_EMBED_BATCH_SIZE,
+            chunking: ChunkOptions {
+                max_file_size: DEFAULT_MAX_FILE_SIZE,
+                ..ChunkOptions::default()
+            },
+            exclude_patterns: compile_exclude_patterns(DEFAULT_EXCLUDE_PATTERNS),
+            all_text_files: false,
+            cache: None,
+            embed_vendor: EmbedVendor::Gemini,
+            embed_model: DEFAULT_EMBED_MODEL.to_string(),
+            index_file: None,
+        }
+    }
+
+    // A store with nothing in it yet, for `add_document`.
+    pub fn empty(api_key: String) -> Self {
+        Self::builder(api_key, &[]).finish(vec![], vec![], HashMap::new())
+    }
+
+    // Store over web pages only, see `RagStoreBuilder::urls`.
+    pub async fn from_urls(api_key: String, urls: &[String]) -> Result<Self> {
+        Self::builder(api_key, &[]).urls(urls).build().await
+    }
+
+    // Watches the context paths and re-indexes files as they are created, modified or
+    // deleted. The watcher lives inside the returned task.
+    pub fn watch(self) -> Result<(Arc<RwLock<RagStore>>, JoinHandle<()>)> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            if let Ok(event) = res {
+                let _ = tx.send(event);
+            }
         })
+        .context("Failed to create file watcher")?;
+        for path in &self.paths {
+            let base = glob_base(path);
+            watcher
+                .watch(&base, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {}", base.display()))?;
+        }
+        let paths = self.paths.clone();
+        let exclude_patterns = self.exclude_patterns.clone();
+        let all_text_files = self.all_text_files;
+
+        let client = self.client.clone();
+        let api_key = self.api_key.clone();
+        let embed_vendor = self.embed_vendor;
+        let embed_model = self.embed_model.clone();
+        let batch_size = self.embed_batch_size;
+        let chunking = self.chunking.clone();
+        let cache = self.cache.clone();
+        let store = Arc::new(RwLock::new(self));
+        let shared = Arc::clone(&store);
+
+        let handle = tokio::spawn(async move {
+            let _watcher = watcher;
+            while let Some(event) = rx.recv().await {
+                // editors fire several events per save, handle whatever has piled up at once
+                let mut changed = Vec::new();
+                let mut next = Some(event);
+                while let Some(event) = next {
+                    if matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    ) {
+                        changed.extend(event.paths);
+                    }
+                    next = rx.try_recv().ok();
+                }
+                changed.sort();
+                changed.dedup();
+                changed.retain(|p| {
+                    (all_text_files || is_text_file(p))
+                        && in_context_paths(&paths, p)
+                        && !is_excluded(&paths, &exclude_patterns, p)
+                });
+                if changed.is_empty() {
+                    continue;
+                }
+
+                // embed outside the lock so searches aren't blocked on the API
+                let mut new_chunks = Vec::new();
+                for path in &changed {
+                    if path.is_file() {
+                        new_chunks.extend(Self::chunk_file(path, &chunking));
+                    }
+                }
+                let new_embeddings = match embed_chunks(
+                    &client,
+                    embed_vendor,
+                    &api_key,
+                    &embed_model,
+                    &new_chunks,
+                    batch_size,
+                    cache.as_ref(),
+                )
+                .await
+                {
+                    Ok(embeddings) => embeddings,
+                    Err(e) => {
+                        eprintln!("Warning: Failed to re-index changed files: {}", e);
+                        continue;
+                    }
+                };
+
+                let mut store = shared.write().await;
+                let sources: Vec<&str> = changed.iter().filter_map(|p| p.to_str()).collect();
+                store.remove_sources(&sources);
+                store.chunks.extend(new_chunks);
+                store.embeddings.extend(new_embeddings);
+            }
+        });
+
+        Ok((store, handle))
+    }
+
+    // Indexes `text` as if it were a file named `source`, e.g. something pasted in a chat.
+    pub async fn add_document(&mut self, source: String, text: String) -> Result<()> {
+        let chunks = self.chunking.chunk(Path::new(&source), &source, &text);
+        let embeddings = embed_chunks(
+            &self.client,
+            self.embed_vendor,
+            &self.api_key,
+            &self.embed_model,
+            &chunks,
+            self.embed_batch_size,
+            self.cache.as_ref(),
+        )
+        .await?;
+        self.chunks.extend(chunks);
+        self.embeddings.extend(embeddings);
+        Ok(())
+    }
+
+    // Drops every chunk whose source starts with `source_prefix`, returns how many.
+    pub fn remove_document(&mut self, source_prefix: &str) -> usize {
+        let before = self.chunks.len();
+        (self.chunks, self.embeddings) = std::mem::take(&mut self.chunks)
+            .into_iter()
+            .zip(std::mem::take(&mut self.embeddings))
+            .filter(|(chunk, _)| !chunk.source.starts_with(source_prefix))
+            .unzip();
+        before - self.chunks.len()
+    }
+
+    // drops every chunk that came from one of `sources` (PDF page sources included)
+    fn remove_sources(&mut self, sources: &[&str]) {
+        let from_sources = |chunk: &TextChunk| {
+            sources.iter().any(|source| {
+                chunk.source == *source
+                    || chunk
+                        .source
+                        .strip_prefix(source)
+                        .is_some_and(|rest| rest.starts_with(":page"))
+            })
+        };
+        (self.chunks, self.embeddings) = std::mem::take(&mut self.chunks)
+            .into_iter()
+            .zip(std::mem::take(&mut self.embeddings))
+            .filter(|(chunk, _)| !from_sources(chunk))
+            .unzip();
+    }
+
+    // lambda < 1.0 makes search() use MMR re-ranking, trading relevance for diversity
+    pub fn with_mmr_lambda(mut self, lambda: f32) -> Self {
+        self.mmr_lambda = lambda.clamp(0.0, 1.0);
+        self
+    }
+
+    // rerank retrieved chunks with Cohere's rerank API before returning them
+    pub fn with_cohere_reranker(mut self, cohere_api_key: String) -> Self {
+        self.cohere_api_key = Some(cohere_api_key);
+        self
+    }
+
+    // The `top_k` chunks most relevant to the query, best first. The order is the final
+    // ranking, which MMR or the reranker may have changed from plain similarity.
+    pub async fn search(&self, query: &str, top_k: usize) -> Result<Vec<SearchResult>> {
+        self.search_mmr(query, top_k, self.mmr_lambda).await
     }
 
-    pub async fn search(&self, query: &str, top_k: usize) -> Result<Vec<String>> {
+    // `search` with an explicit MMR lambda instead of the store's, 1.0 being plain
+    // similarity ranking.
+    pub async fn search_mmr(
+        &self,
+        query: &str,
+        top_k: usize,
+        lambda: f32,
+    ) -> Result<Vec<SearchResult>> {
+        let lambda = lambda.clamp(0.0, 1.0);
         if self.chunks.is_empty() {
             return Ok(vec![]);
         }
-        let query_embedding = embed_batch(&self.client, &self.api_key, vec![query.to_string()])
-            .await?
-            .remove(0);
+        let query_embedding = self.embed_query(query).await?;
 
-        let mut scored_chunks: Vec<_> = self
-            .embeddings
+        // the reranker gets a wider pool to choose from
+        let candidate_k = if self.cohere_api_key.is_some() {
+            top_k * RERANK_CANDIDATE_FACTOR
+        } else {
+            top_k
+        };
+        let mut indices = if lambda < 1.0 {
+            self.top_by_mmr(&query_embedding, candidate_k, lambda)
+        } else {
+            self.top_by_similarity(&query_embedding, candidate_k)
+        };
+
+        if let Some(cohere_api_key) = &self.cohere_api_key {
+            let documents: Vec<&str> = indices
+                .iter()
+                .map(|&idx| self.chunks[idx].text.as_str())
+                .collect();
+            let order =
+                cohere_rerank(&self.client, cohere_api_key, query, &documents, top_k).await?;
+            indices = order.into_iter().map(|pos| indices[pos]).collect();
+        }
+
+        Ok(indices
+            .into_iter()
+            .map(|idx| self.search_result(idx, similarity(&query_embedding, &self.embeddings[idx])))
+            .collect())
+    }
+
+    fn search_result(&self, chunk_index: usize, similarity: f32) -> SearchResult {
+        let chunk = &self.chunks[chunk_index];
+        SearchResult {
+            source: chunk_location(chunk),
+            text: chunk.text.clone(),
+            similarity,
+            chunk_index,
+        }
+    }
+
+    // Like `search`, but `model` first rewrites the query `num_expansions` ways and every
+    // variant is searched. The merged results are ranked by their best similarity to any
+    // variant, which finds chunks worded differently from the query. MMR is not applied;
+    // the reranker, if any, still sees the original query.
+    pub async fn search_with_expansion(
+        &self,
+        query: &str,
+        top_k: usize,
+        num_expansions: usize,
+        model: &dyn LanguageModel,
+    ) -> Result<Vec<SearchResult>> {
+        if self.chunks.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut queries = vec![query.to_string()];
+        queries.extend(expand_query(model, query, num_expansions).await?);
+        let query_embeddings: Vec<Embedding> = embed_batch(
+            &self.client,
+            self.embed_vendor,
+            &self.api_key,
+            &self.embed_model,
+            &queries,
+            self.cache.as_ref(),
+        )
+        .await?
+        .into_iter()
+        .map(compress)
+        .collect();
+        if let Some(query_embedding) = query_embeddings.first() {
+            self.check_dimensions(query_embedding)?;
+        }
+        let best_similarity = |idx: usize| {
+            query_embeddings
+                .iter()
+                .map(|q| similarity(q, &self.embeddings[idx]))
+                .fold(f32::NEG_INFINITY, f32::max)
+        };
+
+        let candidate_k = if self.cohere_api_key.is_some() {
+            top_k * RERANK_CANDIDATE_FACTOR
+        } else {
+            top_k
+        };
+        let mut candidates: Vec<(f32, usize)> = query_embeddings
             .iter()
-            .zip(&self.chunks)
-            .map(|(embedding, chunk)| {
-                let similarity = cos_sim(&query_embedding, embedding);
-                (similarity, chunk)
+            .flat_map(|q| self.top_by_similarity(q, candidate_k))
+            .collect::<HashSet<usize>>()
+            .into_iter()
+            .map(|idx| (best_similarity(idx), idx))
+            .collect();
+        candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        candidates.truncate(candidate_k);
+
+        if let Some(cohere_api_key) = &self.cohere_api_key {
+            let documents: Vec<&str> = candidates
+                .iter()
+                .map(|&(_, idx)| self.chunks[idx].text.as_str())
+                .collect();
+            let order =
+                cohere_rerank(&self.client, cohere_api_key, query, &documents, top_k).await?;
+            candidates = order.into_iter().map(|pos| candidates[pos]).collect();
+        }
+        candidates.truncate(top_k);
+
+        Ok(candidates
+            .into_iter()
+            .map(|(score, idx)| self.search_result(idx, score))
+            .collect())
+    }
+
+    // Every chunk ranked by similarity to the query, the first `top_k` marked as returned.
+    // MMR and the reranker are not applied, so this shows the raw similarity ranking
+    // they start from.
+    pub async fn explain_retrieval(
+        &self,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<RetrievalExplanation>> {
+        const PREVIEW_CHARS: usize = 80;
+        if self.chunks.is_empty() {
+            return Ok(vec![]);
+        }
+        let query_embedding = self.embed_query(query).await?;
+        let ranked = self.top_by_similarity(&query_embedding, self.chunks.len());
+        Ok(ranked
+            .into_iter()
+            .enumerate()
+            .map(|(rank, idx)| {
+                let chunk = &self.chunks[idx];
+                RetrievalExplanation {
+                    rank,
+                    source: chunk_location(chunk),
+                    similarity: similarity(&query_embedding, &self.embeddings[idx]),
+                    text_preview: chunk
+                        .text
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                        .chars()
+                        .take(PREVIEW_CHARS)
+                        .collect(),
+                    was_returned: rank < top_k,
+                }
             })
+            .collect())
+    }
+
+    // Groups the chunks into (at most) `num_clusters` topics with k-means over their
+    // embeddings, and has `model` describe each topic from its most central chunks.
+    pub async fn cluster_chunks(
+        &self,
+        num_clusters: usize,
+        model: &dyn LanguageModel,
+    ) -> Result<Vec<ChunkCluster>> {
+        let vectors: Vec<Vec<f32>> = self.embeddings.iter().map(unit_vector).collect();
+        let mut clusters = Vec::new();
+        for members in kmeans(&vectors, num_clusters) {
+            if members.is_empty() {
+                continue;
+            }
+            let sample: Vec<&str> = members
+                .iter()
+                .take(CLUSTER_LABEL_CHUNKS)
+                .map(|&idx| self.chunks[idx].text.as_str())
+                .collect();
+            let messages = [Message {
+                role: "user".to_string(),
+                content: format!(
+                    "In one sentence, describe this topic:\n\n{}",
+                    sample.join("\n---\n")
+                ),
+            }];
+            let label = model.ask(&messages, &GenerationConfig::default()).await?;
+            clusters.push(ChunkCluster {
+                centroid_label: label.trim().to_string(),
+                chunks: members
+                    .iter()
+                    .map(|&idx| chunk_location(&self.chunks[idx]))
+                    .collect(),
+            });
+        }
+        Ok(clusters)
+    }
+
+    fn top_by_similarity(&self, query_embedding: &Embedding, top_k: usize) -> Vec<usize> {
+        let mut scored: Vec<(f32, usize)> = self
+            .embeddings
+            .iter()
+            .enumerate()
+            .map(|(idx, embedding)| (similarity(query_embedding, embedding), idx))
             .collect();
 
-        scored_chunks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        scored.into_iter().take(top_k).map(|(_, idx)| idx).collect()
+    }
 
-        let context: Vec<String> = scored_chunks
+    // Maximal Marginal Relevance: greedily pick the chunk that is relevant to the query
+    // but least similar to what has already been picked.
+    fn top_by_mmr(&self, query_embedding: &Embedding, top_k: usize, lambda: f32) -> Vec<usize> {
+        let query_sims: Vec<f32> = self
+            .embeddings
             .iter()
-            .take(top_k)
-            .map(|(_, chunk)| format!("---\nSource: {}\n```\n{}\n```\n", chunk.source, chunk.text))
+            .map(|embedding| similarity(query_embedding, embedding))
             .collect();
 
-        Ok(context)
+        let mut selected: Vec<usize> = Vec::new();
+        let mut candidates: Vec<usize> = (0..self.chunks.len()).collect();
+
+        while selected.len() < top_k && !candidates.is_empty() {
+            let mut best_pos = 0;
+            let mut best_score = f32::NEG_INFINITY;
+            for (pos, &idx) in candidates.iter().enumerate() {
+                let redundancy = selected
+                    .iter()
+                    .map(|&s| similarity(&self.embeddings[s], &self.embeddings[idx]))
+                    .fold(0.0, f32::max);
+                let score = lambda * query_sims[idx] - (1.0 - lambda) * redundancy;
+                if score > best_score {
+                    best_score = score;
+                    best_pos = pos;
+                }
+            }
+            selected.push(candidates.swap_remove(best_pos));
+        }
+        selected
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Embedding> {
+        let embedding = compress(
+            embed_batch(
+                &self.client,
+                self.embed_vendor,
+                &self.api_key,
+                &self.embed_model,
+                &[query.to_string()],
+                self.cache.as_ref(),
+            )
+            .await?
+            .remove(0),
+        );
+        self.check_dimensions(&embedding)?;
+        Ok(embedding)
     }
 
-    fn load_and_chunk_files(paths: &[String]) -> Result<Vec<TextChunk>> {
-        const MAX_CHUNK_SIZE: usize = 2000;
-        const CHUNK_OVERLAP: usize = 200;
+    // Vectors from different models can't be compared, and usually differ in length.
+    fn check_dimensions(&self, query_embedding: &Embedding) -> Result<()> {
+        match self.embeddings.first() {
+            Some(stored) if stored.len() != query_embedding.len() => Err(anyhow::anyhow!(
+                "Stored embeddings have {} dimensions but '{}' returns {}, rebuild the index",
+                stored.len(),
+                self.embed_model,
+                query_embedding.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // The chunks of every context file and command output, plus each file's
+    // modification time.
+    async fn load_and_chunk_files(
+        paths: &[String],
+        commands: &[String],
+        exclude_patterns: &[glob::Pattern],
+        all_text_files: bool,
+        chunking: &ChunkOptions,
+    ) -> Result<(Vec<TextChunk>, HashMap<String, SystemTime>)> {
         let mut chunks = Vec::new();
-        for path_str in paths {
-            let path = Path::new(path_str);
-            if path.is_dir() {
-                for entry in WalkDir::new(path)
-                    .into_iter()
-                    .filter_map(Result::ok)
-                    .filter(|e| e.path().is_file() && is_text_file(e.path()))
-                {
-                    if let Ok(content) = std::fs::read_to_string(entry.path()) {
-                        let source = entry.path().to_str().unwrap_or("").to_string();
-                        chunks.extend(chunk_text(&source, &content, MAX_CHUNK_SIZE, CHUNK_OVERLAP));
-                    }
+        let mut mtimes = HashMap::new();
+        for path in context_files(paths, exclude_patterns, all_text_files) {
+            if let Some(mtime) = modified_time(&path) {
+                mtimes.insert(path.to_string_lossy().into_owned(), mtime);
+            }
+            chunks.extend(Self::chunk_file(&path, chunking));
+        }
+        chunks.extend(Self::chunk_commands(commands, chunking).await);
+        let duplicates = dedup_chunks(&mut chunks);
+        if duplicates > 0 {
+            println!("Deduplicated {} identical chunks", duplicates);
+        }
+        Ok((chunks, mtimes))
+    }
+
+    // Re-indexes the context files created, modified or deleted since the store was built
+    // (or last refreshed). Web pages and command output are left as they are.
+    pub async fn refresh(&mut self) -> Result<()> {
+        let mut mtimes = HashMap::new();
+        let mut changed = Vec::new();
+        for path in context_files(&self.paths, &self.exclude_patterns, self.all_text_files) {
+            let Some(mtime) = modified_time(&path) else {
+                continue;
+            };
+            let source = path.to_string_lossy().into_owned();
+            if self.mtimes.get(&source) != Some(&mtime) {
+                changed.push(path);
+            }
+            mtimes.insert(source, mtime);
+        }
+        let deleted: Vec<String> = self
+            .mtimes
+            .keys()
+            .filter(|source| !mtimes.contains_key(*source))
+            .cloned()
+            .collect();
+        if changed.is_empty() && deleted.is_empty() {
+            return Ok(());
+        }
+
+        let new_chunks: Vec<TextChunk> = changed
+            .iter()
+            .flat_map(|path| Self::chunk_file(path, &self.chunking))
+            .collect();
+        let new_embeddings = embed_chunks(
+            &self.client,
+            self.embed_vendor,
+            &self.api_key,
+            &self.embed_model,
+            &new_chunks,
+            self.embed_batch_size,
+            self.cache.as_ref(),
+        )
+        .await?;
+
+        let sources: Vec<&str> = changed
+            .iter()
+            .filter_map(|path| path.to_str())
+            .chain(deleted.iter().map(String::as_str))
+            .collect();
+        self.remove_sources(&sources);
+        self.chunks.extend(new_chunks);
+        self.embeddings.extend(new_embeddings);
+        self.mtimes = mtimes;
+        println!(
+            "Re-indexed {} changed and {} deleted file(s).",
+            changed.len(),
+            deleted.len()
+        );
+        Ok(())
+    }
+
+    pub fn stats(&self) -> RagStoreStats {
+        let unique_sources: Vec<String> = self
+            .chunks
+            .iter()
+            .map(|chunk| chunk.source.clone())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        RagStoreStats {
+            num_chunks: self.chunks.len(),
+            num_sources: unique_sources.len(),
+            unique_sources,
+            total_text_bytes: self.chunks.iter().map(|chunk| chunk.text.len()).sum(),
+            embedding_dimensions: self.embeddings.first().map_or(0, Vec::len),
+            approx_memory_bytes: self
+                .embeddings
+                .iter()
+                .map(|embedding| std::mem::size_of_val(embedding.as_slice()))
+                .sum(),
+        }
+    }
+
+    // Replaces the chunks of an earlier run of the context commands with fresh output.
+    async fn rerun_commands(&mut self, commands: &[String]) -> Result<()> {
+        let old_sources: Vec<String> = self
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.source.starts_with(COMMAND_SOURCE_PREFIX))
+            .map(|chunk| chunk.source.clone())
+            .collect();
+        if commands.is_empty() && old_sources.is_empty() {
+            return Ok(());
+        }
+        let old_sources: Vec<&str> = old_sources.iter().map(String::as_str).collect();
+        self.remove_sources(&old_sources);
+
+        let new_chunks = Self::chunk_commands(commands, &self.chunking).await;
+        let new_embeddings = embed_chunks(
+            &self.client,
+            self.embed_vendor,
+            &self.api_key,
+            &self.embed_model,
+            &new_chunks,
+            self.embed_batch_size,
+            self.cache.as_ref(),
+        )
+        .await?;
+        self.chunks.extend(new_chunks);
+        self.embeddings.extend(new_embeddings);
+        Ok(())
+    }
+
+    async fn chunk_commands(commands: &[String], chunking: &ChunkOptions) -> Vec<TextChunk> {
+        let mut chunks = Vec::new();
+        for cmd in commands {
+            match run_context_command(cmd).await {
+                Ok(output) => {
+                    let source = format!("{}{}", COMMAND_SOURCE_PREFIX, cmd);
+                    chunks.extend(chunking.chunk(Path::new(""), &source, &output));
                 }
-            } else if path.is_file() && is_text_file(path) {
-                if let Ok(content) = std::fs::read_to_string(path) {
-                    let source = path.to_str().unwrap_or("").to_string();
-                    chunks.extend(chunk_text(&source, &content, MAX_CHUNK_SIZE, CHUNK_OVERLAP));
+                Err(e) => println!("Warning: Skipping command `{}`: {}", cmd, e),
+            }
+        }
+        chunks
+    }
+
+    fn chunk_file(path: &Path, chunking: &ChunkOptions) -> Vec<TextChunk> {
+        let source = path.to_str().unwrap_or("").to_string();
+        if let Ok(meta) = std::fs::metadata(path)
+            && meta.len() > chunking.max_file_size
+        {
+            println!(
+                "Skipping large file: {} ({:.1} MB > {:.1} MB limit)",
+                source,
+                meta.len() as f64 / 1e6,
+                chunking.max_file_size as f64 / 1e6
+            );
+            return vec![];
+        }
+        let chunk = |source: &str, text: &str| chunking.chunk(path, source, text);
+
+        if is_pdf(path) {
+            return match read_pdf_as_text(path) {
+                // pages are separated by form feeds, keep the page number in the source
+                Ok(content) => content
+                    .split('\x0c')
+                    .enumerate()
+                    .filter(|(_, page)| !page.trim().is_empty())
+                    .flat_map(|(i, page)| {
+                        let page_source = format!("{}:page{}", source, i + 1);
+                        chunk(&page_source, page)
+                    })
+                    .collect(),
+                Err(e) => {
+                    println!("Warning: Skipping PDF {}: {}", source, e);
+                    vec![]
                 }
+            };
+        }
+
+        match std::fs::read_to_string(path) {
+            Ok(content) => chunk(&source, &content),
+            Err(_) => vec![],
+        }
+    }
+}
+
+// Whole files under `paths`, each cut to `max_chars` characters, for when nothing can be
+// embedded. Formatted like search results so they can be mixed with them.
+pub fn raw_context(paths: &[String], max_chars: usize) -> Vec<String> {
+    let mut snippets = Vec::new();
+    for path_str in paths {
+        for path in expand_context_path(path_str) {
+            if !(path.is_file() && is_text_file(&path)) {
+                continue;
+            }
+            let content = if is_pdf(&path) {
+                read_pdf_as_text(&path)
+            } else {
+                std::fs::read_to_string(&path).map_err(Into::into)
+            };
+            match content {
+                Ok(content) => snippets.push(format!(
+                    "---\nSource: {}\n```\n{}\n```\n",
+                    path.display(),
+                    content.chars().take(max_chars).collect::<String>()
+                )),
+                Err(e) => println!("Warning: Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+    snippets
+}
+
+// Runs a `context_commands` entry with `sh -c` and returns its stdout, cut to
+// MAX_COMMAND_OUTPUT bytes. Fails if the command exits unsuccessfully or runs longer than
+// COMMAND_TIMEOUT.
+async fn run_context_command(cmd: &str) -> Result<String> {
+    use tokio::io::AsyncReadExt;
+
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to start sh")?;
+    let stdout = child.stdout.take().context("stdout not captured")?;
+    // reading stops (closing the pipe) once past the limit, and a background process
+    // started by the command can keep the pipe open, so both count against the timeout
+    let run = async {
+        let mut output = Vec::new();
+        let mut limited = stdout.take(MAX_COMMAND_OUTPUT as u64 + 1);
+        limited.read_to_end(&mut output).await?;
+        drop(limited);
+        Ok::<_, std::io::Error>((output, child.wait().await?))
+    };
+    let Ok(result) = tokio::time::timeout(COMMAND_TIMEOUT, run).await else {
+        let _ = child.kill().await;
+        return Err(anyhow::anyhow!(
+            "timed out after {} seconds",
+            COMMAND_TIMEOUT.as_secs()
+        ));
+    };
+    let (mut output, status) = result?;
+    // a command cut off by the closed pipe exits with an error, keep what it wrote
+    if output.len() > MAX_COMMAND_OUTPUT {
+        println!(
+            "Warning: Output of `{}` cut to {} KB",
+            cmd,
+            MAX_COMMAND_OUTPUT / 1000
+        );
+        output.truncate(MAX_COMMAND_OUTPUT);
+    } else if !status.success() {
+        return Err(anyhow::anyhow!("exited with {}", status));
+    }
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+// The rows of a `context_sqlite` table as (source, text) pairs, with sources like
+// "sqlite:notes.db:notes:42". Rows whose text is NULL or empty are left out.
+fn read_sqlite_rows(config: &SqliteContextConfig) -> Result<Vec<(String, String)>> {
+    let path = Path::new(&config.path);
+    let conn = rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .with_context(|| format!("Failed to open {}", path.display()))?;
+    // identifiers can't be bound as parameters, quote them instead
+    let quote = |name: &str| format!("\"{}\"", name.replace('"', "\"\""));
+    let sql = format!(
+        "SELECT CAST({} AS TEXT), CAST({} AS TEXT) FROM {}",
+        quote(&config.id_column),
+        quote(&config.text_column),
+        quote(&config.table)
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, Option<String>>(0)?,
+            row.get::<_, Option<String>>(1)?,
+        ))
+    })?;
+
+    let mut texts = Vec::new();
+    for row in rows {
+        let (id, text) = row?;
+        let Some(text) = text.filter(|text| !text.trim().is_empty()) else {
+            continue;
+        };
+        let source = format!(
+            "sqlite:{}:{}:{}",
+            config.path,
+            config.table,
+            id.unwrap_or_default()
+        );
+        texts.push((source, text));
+    }
+    Ok(texts)
+}
+
+// Drops chunks whose text already appeared, keeping the one from the lexicographically
+// first source so the result doesn't depend on directory walk order. Returns how many
+// were dropped.
+fn dedup_chunks(chunks: &mut Vec<TextChunk>) -> usize {
+    let mut order: Vec<usize> = (0..chunks.len()).collect();
+    order.sort_by(|&a, &b| chunks[a].source.cmp(&chunks[b].source));
+    let mut seen = HashSet::new();
+    let mut keep = vec![false; chunks.len()];
+    for idx in order {
+        keep[idx] = seen.insert(Sha256::digest(chunks[idx].text.as_bytes()));
+    }
+    let before = chunks.len();
+    let mut keep = keep.into_iter();
+    chunks.retain(|_| keep.next().unwrap_or(true));
+    before - chunks.len()
+}
+
+fn chunk_location(chunk: &TextChunk) -> String {
+    format!("{}:{}-{}", chunk.source, chunk.start_line, chunk.end_line)
+}
+
+// Lloyd's algorithm over unit vectors, so a dot product is the cosine similarity. It
+// starts from evenly spaced vectors to stay deterministic. Returns the members of each
+// cluster, most similar to its centroid first.
+fn kmeans(vectors: &[Vec<f32>], k: usize) -> Vec<Vec<usize>> {
+    if vectors.is_empty() {
+        return vec![];
+    }
+    let k = k.clamp(1, vectors.len());
+    let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let nearest = |centroids: &[Vec<f32>], v: &[f32]| {
+        centroids
+            .iter()
+            .map(|c| dot(c, v))
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, 0.0))
+    };
+
+    let mut centroids: Vec<Vec<f32>> = (0..k)
+        .map(|i| vectors[i * vectors.len() / k].clone())
+        .collect();
+    let mut assignments = Vec::new();
+    for _ in 0..KMEANS_MAX_ITERATIONS {
+        let next: Vec<usize> = vectors.iter().map(|v| nearest(&centroids, v).0).collect();
+        if next == assignments {
+            break;
+        }
+        assignments = next;
+        for (cluster, centroid) in centroids.iter_mut().enumerate() {
+            let mut sum = vec![0.0; centroid.len()];
+            let members = vectors
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, a)| **a == cluster);
+            for (v, _) in members {
+                sum.iter_mut().zip(v).for_each(|(s, x)| *s += x);
+            }
+            // an empty cluster keeps its old centroid
+            if sum.iter().any(|x| *x != 0.0) {
+                *centroid = normalized(sum);
             }
         }
-        Ok(chunks)
+    }
+
+    let mut clusters: Vec<Vec<(usize, f32)>> = vec![Vec::new(); k];
+    for (idx, v) in vectors.iter().enumerate() {
+        let (cluster, sim) = nearest(&centroids, v);
+        clusters[cluster].push((idx, sim));
+    }
+    clusters
+        .into_iter()
+        .map(|mut members| {
+            members.sort_by(|a, b| b.1.total_cmp(&a.1));
+            members.into_iter().map(|(idx, _)| idx).collect()
+        })
+        .collect()
+}
+
+fn normalized(mut v: Vec<f32>) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+#[cfg(not(feature = "compressed-embeddings"))]
+fn unit_vector(embedding: &Embedding) -> Vec<f32> {
+    normalized(embedding.clone())
+}
+
+#[cfg(feature = "compressed-embeddings")]
+fn unit_vector(embedding: &Embedding) -> Vec<f32> {
+    normalized(
+        embedding
+            .iter()
+            .map(|&x| half::f16::from_bits(x).to_f32())
+            .collect(),
+    )
+}
+
+// Up to `num_expansions` rephrasings of a search query, asked for one per line.
+async fn expand_query(
+    model: &dyn LanguageModel,
+    query: &str,
+    num_expansions: usize,
+) -> Result<Vec<String>> {
+    if num_expansions == 0 {
+        return Ok(vec![]);
+    }
+    let messages = [Message {
+        role: "user".to_string(),
+        content: format!(
+            "Write {} alternative phrasings of this search query, using different words \
+             where you can. Reply with one per line and nothing else.\n\nQuery: {}",
+            num_expansions, query
+        ),
+    }];
+    let reply = model.ask(&messages, &GenerationConfig::default()).await?;
+    Ok(reply
+        .lines()
+        // models like to number or bullet the lines anyway
+        .map(|line| {
+            let line = line.trim();
+            let unnumbered = line
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .strip_prefix(['.', ')']);
+            unnumbered
+                .or_else(|| line.strip_prefix(['-', '*']))
+                .unwrap_or(line)
+                .trim()
+                .trim_matches('"')
+        })
+        .filter(|line| !line.is_empty() && !line.eq_ignore_ascii_case(query))
+        .take(num_expansions)
+        .map(str::to_string)
+        .collect())
+}
+
+// How a store reads and splits files: the size limit, strategy and per-extension chunk
+// sizes.
+#[derive(Clone, Default)]
+struct ChunkOptions {
+    // in bytes, larger files are skipped
+    max_file_size: u64,
+    // None picks a strategy per file type
+    strategy: Option<ChunkStrategy>,
+    // keyed by lowercase extension without the dot
+    size_by_extension: HashMap<String, usize>,
+    overlap_by_extension: HashMap<String, usize>,
+}
+
+impl ChunkOptions {
+    // Chunks `text` the way a file at `path` is chunked.
+    fn chunk(&self, path: &Path, source: &str, text: &str) -> Vec<TextChunk> {
+        let strategy = self
+            .strategy
+            .unwrap_or_else(|| default_chunk_strategy(path));
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase)
+            .unwrap_or_default();
+        let size = self
+            .size_by_extension
+            .get(&ext)
+            .copied()
+            .unwrap_or(MAX_CHUNK_SIZE)
+            .max(1);
+        // an overlap of a whole chunk would never advance
+        let overlap = self
+            .overlap_by_extension
+            .get(&ext)
+            .copied()
+            .unwrap_or(CHUNK_OVERLAP)
+            .min(size - 1);
+        match strategy {
+            ChunkStrategy::Fixed => chunk_text(source, text, size, overlap),
+            ChunkStrategy::Paragraphs => chunk_by_paragraphs(source, text, size, overlap),
+        }
     }
 }
 
 fn chunk_text(source: &str, text: &str, max_size: usize, overlap: usize) -> Vec<TextChunk> {
+    // number of the last line in `slice`, ignoring a trailing newline
+    let last_line = |start_line: usize, slice: &str| {
+        start_line + slice.trim_end_matches('\n').matches('\n').count()
+    };
     if text.len() <= max_size {
         return vec![TextChunk {
             source: source.to_string(),
             text: text.to_string(),
+            start_line: 1,
+            end_line: last_line(1, text),
         }];
     }
     let mut chunks = Vec::new();
     let mut start = 0;
+    // line number at `start`, advanced incrementally instead of rescanning from 0
+    let mut start_line = 1;
     while start < text.len() {
-        let end = std::cmp::min(start + max_size, text.len());
+        let end = text.ceil_char_boundary(std::cmp::min(start + max_size, text.len()));
+        let slice = &text[start..end];
         chunks.push(TextChunk {
             source: source.to_string(),
-            text: text[start..end].to_string(),
+            text: slice.to_string(),
+            start_line,
+            end_line: last_line(start_line, slice),
         });
         if end == text.len() {
             break;
         }
-        start += max_size - overlap;
+        let next = text.ceil_char_boundary(start + max_size - overlap);
+        start_line += text[start..next].matches('\n').count();
+        start = next;
+    }
+    chunks
+}
+
+fn has_glob_chars(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+// Expands one `context_paths` entry: a directory means everything below it, anything
+// else is treated as a glob pattern. Plain paths still work as single-match patterns.
+pub fn expand_context_path(path_str: &str) -> Vec<PathBuf> {
+    let path = Path::new(path_str);
+    let pattern = if path.is_dir() {
+        format!(
+            "{}/**/*",
+            glob::Pattern::escape(path_str.trim_end_matches('/'))
+        )
+    } else {
+        path_str.to_string()
+    };
+
+    let matches: Vec<PathBuf> = match glob::glob(&pattern) {
+        Ok(paths) => paths.filter_map(Result::ok).collect(),
+        Err(e) => {
+            println!(
+                "Warning: Invalid context path pattern '{}': {}",
+                path_str, e
+            );
+            vec![]
+        }
+    };
+    if !matches.is_empty() {
+        return matches;
+    }
+
+    if path.exists() {
+        // e.g. a literal file name that happens to contain `[`
+        vec![path.to_path_buf()]
+    } else if has_glob_chars(path_str) {
+        println!("Warning: Context pattern matched no files: {}", path_str);
+        vec![]
+    } else {
+        println!("Warning: Context path does not exist: {}", path_str);
+        vec![]
+    }
+}
+
+// the leading part of a context path without glob characters, i.e. something we can watch
+fn glob_base(path_str: &str) -> PathBuf {
+    let base: PathBuf = Path::new(path_str)
+        .components()
+        .take_while(|c| !has_glob_chars(&c.as_os_str().to_string_lossy()))
+        .collect();
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}
+
+// Invalid patterns are skipped with a warning.
+fn compile_exclude_patterns<S: AsRef<str>>(patterns: &[S]) -> Vec<glob::Pattern> {
+    patterns
+        .iter()
+        .filter_map(|pattern| {
+            glob::Pattern::new(pattern.as_ref())
+                .inspect_err(|e| {
+                    println!(
+                        "Warning: Invalid exclude pattern '{}': {}",
+                        pattern.as_ref(),
+                        e
+                    )
+                })
+                .ok()
+        })
+        .collect()
+}
+
+// `file` as given plus relative to the working directory, since watcher events can
+// come back absolute while context paths are often relative
+fn path_candidates(file: &Path) -> Vec<PathBuf> {
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| file.strip_prefix(cwd).ok().map(Path::to_path_buf));
+    std::iter::once(file.to_path_buf())
+        .chain(relative)
+        .collect()
+}
+
+// Every indexable file under the context paths.
+fn context_files(
+    paths: &[String],
+    exclude_patterns: &[glob::Pattern],
+    all_text_files: bool,
+) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .flat_map(|path_str| expand_context_path(path_str))
+        .filter(|path| {
+            path.is_file()
+                && (all_text_files || is_text_file(path))
+                && !is_excluded(paths, exclude_patterns, path)
+        })
+        .collect()
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+// whether a file inside one of the context directories matches an exclude pattern,
+// files picked by a file path or glob pattern are never excluded
+fn is_excluded(paths: &[String], exclude_patterns: &[glob::Pattern], file: &Path) -> bool {
+    let candidates = path_candidates(file);
+    paths
+        .iter()
+        .filter(|path_str| Path::new(path_str).is_dir())
+        .any(|dir| {
+            candidates.iter().any(|f| {
+                f.strip_prefix(dir).is_ok_and(|relative| {
+                    exclude_patterns
+                        .iter()
+                        .any(|pattern| pattern.matches_path(relative))
+                })
+            })
+        })
+}
+
+// whether a changed file falls under one of the context paths / patterns
+fn in_context_paths(paths: &[String], file: &Path) -> bool {
+    let candidates = path_candidates(file);
+
+    paths.iter().any(|path_str| {
+        if has_glob_chars(path_str) {
+            glob::Pattern::new(path_str)
+                .is_ok_and(|pattern| candidates.iter().any(|f| pattern.matches_path(f)))
+        } else {
+            candidates.iter().any(|f| f.starts_with(path_str))
+        }
+    })
+}
+
+fn default_chunk_strategy(path: &Path) -> ChunkStrategy {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("txt") => {
+            ChunkStrategy::Paragraphs
+        }
+        _ => ChunkStrategy::Fixed,
+    }
+}
+
+// byte range and line range of a run of text
+#[derive(Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    start_line: usize,
+    end_line: usize,
+}
+
+// Splits on blank lines and packs consecutive paragraphs into chunks of up to `max_size`.
+// Only a paragraph that is too big on its own gets the fixed-window treatment (with `overlap`).
+fn chunk_by_paragraphs(
+    source: &str,
+    text: &str,
+    max_size: usize,
+    overlap: usize,
+) -> Vec<TextChunk> {
+    let mut paragraphs: Vec<Span> = Vec::new();
+    let mut current: Option<Span> = None;
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        if line.trim().is_empty() {
+            paragraphs.extend(current.take());
+        } else {
+            let end = offset + line.trim_end().len();
+            match &mut current {
+                Some(span) => {
+                    span.end = end;
+                    span.end_line = i + 1;
+                }
+                None => {
+                    current = Some(Span {
+                        start: offset,
+                        end,
+                        start_line: i + 1,
+                        end_line: i + 1,
+                    })
+                }
+            }
+        }
+        offset += line.len();
+    }
+    paragraphs.extend(current);
+
+    let to_chunk = |span: Span| TextChunk {
+        source: source.to_string(),
+        text: text[span.start..span.end].to_string(),
+        start_line: span.start_line,
+        end_line: span.end_line,
+    };
+
+    let mut chunks = Vec::new();
+    let mut group: Option<Span> = None;
+    for para in paragraphs {
+        if para.end - para.start > max_size {
+            chunks.extend(group.take().map(to_chunk));
+            let line_offset = para.start_line - 1;
+            chunks.extend(
+                chunk_text(source, &text[para.start..para.end], max_size, overlap)
+                    .into_iter()
+                    .map(|mut chunk| {
+                        chunk.start_line += line_offset;
+                        chunk.end_line += line_offset;
+                        chunk
+                    }),
+            );
+            continue;
+        }
+        group = match group {
+            Some(g) if para.end - g.start <= max_size => Some(Span {
+                end: para.end,
+                end_line: para.end_line,
+                ..g
+            }),
+            other => {
+                chunks.extend(other.map(to_chunk));
+                Some(para)
+            }
+        };
     }
+    chunks.extend(group.map(to_chunk));
     chunks
 }
 
+// Known extensions are accepted without opening the file. Anything else (Makefile,
+// Dockerfile, .ini, ...) counts as text unless its first bytes contain a null byte.
 fn is_text_file(path: &Path) -> bool {
     const TEXT_EXTENSIONS: &[&str] = &[
         "rs", "toml", "md", "txt", "json", "yaml", "yml", "html", "css", "js", "ts", "py", "go",
-        "c", "cpp", "h", "hpp", "php", "sh", "sql",
+        "c", "cpp", "h", "hpp", "php", "sh", "sql", "pdf",
     ];
+    let known = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext_str| TEXT_EXTENSIONS.contains(&ext_str.to_lowercase().as_str()));
+    known || looks_like_text(path)
+}
+
+fn looks_like_text(path: &Path) -> bool {
+    const SNIFF_LEN: u64 = 512;
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    let mut head = Vec::new();
+    file.take(SNIFF_LEN).read_to_end(&mut head).is_ok() && !head.contains(&0)
+}
+
+fn is_pdf(path: &Path) -> bool {
     path.extension()
         .and_then(|ext| ext.to_str())
-        .map(|ext_str| TEXT_EXTENSIONS.contains(&ext_str.to_lowercase().as_str()))
-        .unwrap_or(false)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pdf"))
+}
+
+// Extracts the text of every page, pages separated by a form feed.
+fn read_pdf_as_text(path: &Path) -> Result<String> {
+    let doc = lopdf::Document::load(path).context("Failed to open PDF")?;
+    if doc.is_encrypted() {
+        return Err(anyhow::anyhow!("PDF is encrypted"));
+    }
+    let mut pages = Vec::new();
+    for page_num in doc.get_pages().keys() {
+        pages.push(doc.extract_text(&[*page_num]).unwrap_or_default());
+    }
+    Ok(pages.join("\x0c"))
+}
+
+// One-off embedding outside of any store.
+pub async fn embed(api_key: &str, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    embed_batch(
+        &http_client(),
+        EmbedVendor::Gemini,
+        api_key,
+        DEFAULT_EMBED_MODEL,
+        texts,
+        None,
+    )
+    .await
+}
+
+async fn embed_chunks(
+    client: &reqwest::Client,
+    vendor: EmbedVendor,
+    api_key: &str,
+    model: &str,
+    chunks: &[TextChunk],
+    batch_size: usize,
+    cache: Option<&SharedEmbeddingCache>,
+) -> Result<Vec<Embedding>> {
+    let documents: Vec<String> = chunks.iter().map(|c| c.text.clone()).collect();
+    let mut embeddings = Vec::with_capacity(documents.len());
+    let progress = embed_progress_bar(documents.len());
+    for batch in documents.chunks(batch_size.max(1)) {
+        embeddings.extend(
+            embed_with_retry(client, vendor, api_key, model, batch, cache)
+                .await?
+                .into_iter()
+                .map(compress),
+        );
+        progress.inc(batch.len() as u64);
+    }
+    progress.finish();
+    Ok(embeddings)
+}
+
+// Ticks once per embedded batch. Hidden with --quiet or when stdout isn't a terminal.
+fn embed_progress_bar(len: usize) -> indicatif::ProgressBar {
+    if HIDE_PROGRESS.load(Ordering::Relaxed) || !std::io::stdout().is_terminal() {
+        return indicatif::ProgressBar::hidden();
+    }
+    let style = indicatif::ProgressStyle::with_template(
+        "[{bar:20}] {pos}/{len} chunks embedded (ETA {eta})",
+    )
+    .expect("valid progress template")
+    .progress_chars("#-");
+    indicatif::ProgressBar::with_draw_target(
+        Some(len as u64),
+        indicatif::ProgressDrawTarget::stdout(),
+    )
+    .with_style(style)
+}
+
+// Non-success reply from the embedding API, kept typed so callers can react to the status.
+#[derive(Debug)]
+pub struct EmbeddingApiError {
+    pub status: reqwest::StatusCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for EmbeddingApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "API embedding failed ({}): {}",
+            self.status, self.message
+        )
+    }
 }
 
+impl std::error::Error for EmbeddingApiError {}
+
+// On a 400 the batch is split in half and each half retried, down to single texts,
+// so one oversized chunk doesn't take the whole batch down with it.
+async fn embed_with_retry(
+    client: &reqwest::Client,
+    vendor: EmbedVendor,
+    api_key: &str,
+    model: &str,
+    texts: &[String],
+    cache: Option<&SharedEmbeddingCache>,
+) -> Result<Vec<Vec<f32>>> {
+    match embed_batch(client, vendor, api_key, model, texts, cache).await {
+        Err(e)
+            if texts.len() > 1
+                && e.downcast_ref::<EmbeddingApiError>()
+                    .is_some_and(|api_err| api_err.status == reqwest::StatusCode::BAD_REQUEST) =>
+        {
+            let (left, right) = texts.split_at(texts.len() / 2);
+            let mut embeddings = Box::pin(embed_with_retry(
+                client, vendor, api_key, model, left, cache,
+            ))
+            .await?;
+            embeddings.extend(
+                Box::pin(embed_with_retry(
+                    client, vendor, api_key, model, right, cache,
+                ))
+                .await?,
+            );
+            Ok(embeddings)
+        }
+        result => result,
+    }
+}
+
+// The raw embedding of `text`, for `aiterm embed`.
+pub async fn embed_text(api_key: &str, text: &str) -> Result<Vec<f32>> {
+    Ok(embed_batch(
+        &http_client(),
+        EmbedVendor::Gemini,
+        api_key,
+        DEFAULT_EMBED_MODEL,
+        &[text.to_string()],
+        None,
+    )
+    .await?
+    .remove(0))
+}
+
+// Similarity of two texts as retrieval scores it, for `aiterm embed-similarity`.
+pub async fn text_similarity(api_key: &str, a: &str, b: &str) -> Result<f32> {
+    let mut embeddings = embed_batch(
+        &http_client(),
+        EmbedVendor::Gemini,
+        api_key,
+        DEFAULT_EMBED_MODEL,
+        &[a.to_string(), b.to_string()],
+        None,
+    )
+    .await?
+    .into_iter()
+    .map(compress);
+    let (Some(a), Some(b)) = (embeddings.next(), embeddings.next()) else {
+        unreachable!("embed_batch returns one embedding per text");
+    };
+    Ok(similarity(&a, &b))
+}
+
+// Cache hits are served locally, only the misses go to the API.
 async fn embed_batch(
     client: &reqwest::Client,
+    vendor: EmbedVendor,
     api_key: &str,
-    texts: Vec<String>,
+    model: &str,
+    texts: &[String],
+    cache: Option<&SharedEmbeddingCache>,
+) -> Result<Vec<Vec<f32>>> {
+    let mut results: Vec<Option<Vec<f32>>> = match cache {
+        Some(cache) => {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            texts.iter().map(|text| cache.get(model, text)).collect()
+        }
+        None => vec![None; texts.len()],
+    };
+    let missing: Vec<usize> = (0..texts.len()).filter(|&i| results[i].is_none()).collect();
+    if missing.is_empty() {
+        return Ok(results.into_iter().flatten().collect());
+    }
+
+    let missing_texts: Vec<String> = missing.iter().map(|&i| texts[i].clone()).collect();
+    let embeddings = match vendor {
+        EmbedVendor::Gemini => embed_gemini(client, api_key, model, &missing_texts).await?,
+        EmbedVendor::Together => embed_together(client, api_key, model, &missing_texts).await?,
+    };
+    if embeddings.len() != missing.len() {
+        return Err(anyhow::anyhow!(
+            "Embedding API returned {} embeddings for {} texts",
+            embeddings.len(),
+            missing.len()
+        ));
+    }
+
+    let mut cache = cache.map(|c| c.lock().unwrap_or_else(PoisonError::into_inner));
+    for (&i, embedding) in missing.iter().zip(embeddings) {
+        if let Some(cache) = cache.as_mut() {
+            cache.put(model, &texts[i], embedding.clone());
+        }
+        results[i] = Some(embedding);
+    }
+    Ok(results.into_iter().flatten().collect())
+}
+
+async fn embed_gemini(
+    client: &reqwest::Client,
+    api_key: &str,
+    model: &str,
+    texts: &[String],
 ) -> Result<Vec<Vec<f32>>> {
     let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/text-embedding-004:batchEmbedContents?key={}",
-        api_key
+        "https://generativelanguage.googleapis.com/v1beta/models/{}:batchEmbedContents?key={}",
+        model, api_key
     );
 
     let requests: Vec<EmbeddingRequest> = texts
-        .into_iter()
+        .iter()
         .map(|text| EmbeddingRequest {
-            model: "models/text-embedding-004".to_string(),
+            model: format!("models/{}", model),
             content: Content {
-                parts: vec![Part { text }],
+                parts: vec![Part { text: text.clone() }],
             },
         })
         .collect();
@@ -194,33 +1961,152 @@ async fn embed_batch(
         .send()
         .await
         .context("Failed to send embedding request to API")?;
+    let response_body: BatchEmbeddingResponse = embedding_response(res).await?;
+    Ok(response_body
+        .embeddings
+        .into_iter()
+        .map(|embedding| embedding.values)
+        .collect())
+}
 
+async fn embed_together(
+    client: &reqwest::Client,
+    api_key: &str,
+    model: &str,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let res = client
+        .post("https://api.together.xyz/v1/embeddings")
+        .bearer_auth(api_key)
+        .json(&TogetherEmbeddingRequest {
+            model,
+            input: texts,
+        })
+        .send()
+        .await
+        .context("Failed to send embedding request to Together AI")?;
+    let mut response_body: TogetherEmbeddingResponse = embedding_response(res).await?;
+    // not promised to come back in input order
+    response_body.data.sort_by_key(|embedding| embedding.index);
+    Ok(response_body
+        .data
+        .into_iter()
+        .map(|embedding| embedding.embedding)
+        .collect())
+}
+
+// The parsed body of a successful reply, an EmbeddingApiError otherwise.
+async fn embedding_response<T: serde::de::DeserializeOwned>(res: reqwest::Response) -> Result<T> {
     if !res.status().is_success() {
+        let status = res.status();
         let error_text = res
             .text()
             .await
             .unwrap_or_else(|_| "Unknown API error".to_string());
-        return Err(anyhow::anyhow!("API embedding failed: {}", error_text));
+        return Err(EmbeddingApiError {
+            status,
+            message: error_text,
+        }
+        .into());
     }
+    res.json()
+        .await
+        .context("Failed to parse embedding response")
+}
 
-    let response_body: BatchEmbeddingResponse = res
+// Returns positions into `documents`, most relevant first.
+async fn cohere_rerank(
+    client: &reqwest::Client,
+    api_key: &str,
+    query: &str,
+    documents: &[&str],
+    top_n: usize,
+) -> Result<Vec<usize>> {
+    if documents.is_empty() {
+        return Ok(vec![]);
+    }
+    let res = client
+        .post("https://api.cohere.com/v1/rerank")
+        .bearer_auth(api_key)
+        .json(&RerankRequest {
+            model: "rerank-english-v3.0",
+            query,
+            documents,
+            top_n,
+        })
+        .send()
+        .await
+        .context("Failed to send rerank request to Cohere")?;
+
+    if !res.status().is_success() {
+        let error_text = res
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown API error".to_string());
+        return Err(anyhow::anyhow!("Cohere rerank failed: {}", error_text));
+    }
+
+    let response_body: RerankResponse = res
         .json()
         .await
-        .context("Failed to parse embedding response")?;
-    Ok(response_body
-        .embeddings
+        .context("Failed to parse rerank response")?;
+    Ok(response_body.results.into_iter().map(|r| r.index).collect())
+}
+
+#[cfg(not(feature = "compressed-embeddings"))]
+fn compress(embedding: Vec<f32>) -> Embedding {
+    embedding
+}
+
+#[cfg(feature = "compressed-embeddings")]
+fn compress(embedding: Vec<f32>) -> Embedding {
+    embedding
         .into_iter()
-        .map(|e| e.values)
-        .collect())
+        .map(|x| half::f16::from_f32(x).to_bits())
+        .collect()
 }
 
-// Calculates cosine similarity between two vectors.
-fn cos_sim(a: &[f32], b: &[f32]) -> f32 {
-    let dot_product = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
-    let norm_a = a.iter().map(|x| x.powi(2)).sum::<f32>().sqrt();
-    let norm_b = b.iter().map(|x| x.powi(2)).sum::<f32>().sqrt();
-    if norm_a == 0.0 || norm_b == 0.0 {
-        return 0.0;
+#[cfg(not(feature = "compressed-embeddings"))]
+fn similarity(a: &Embedding, b: &Embedding) -> f32 {
+    cos_sim(a, b)
+}
+
+#[cfg(feature = "compressed-embeddings")]
+fn similarity(a: &Embedding, b: &Embedding) -> f32 {
+    cos_sim_f16(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_rows_are_read_from_quoted_identifiers() {
+        let path = std::env::temp_dir().join(format!("aiterm-test-{}-rows.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            r#"CREATE TABLE "my ""notes""" ("note id" INTEGER, "body text" TEXT);
+               INSERT INTO "my ""notes""" VALUES (1, 'first'), (2, NULL), (3, '  '), (4, 'fourth');"#,
+        )
+        .unwrap();
+        drop(conn);
+
+        let config = SqliteContextConfig {
+            path: path.to_string_lossy().into_owned(),
+            table: r#"my "notes""#.to_string(),
+            text_column: "body text".to_string(),
+            id_column: "note id".to_string(),
+        };
+        let rows = read_sqlite_rows(&config).unwrap();
+        let source = |id| format!("sqlite:{}:my \"notes\":{}", config.path, id);
+        assert_eq!(
+            rows,
+            [
+                (source(1), "first".to_string()),
+                (source(4), "fourth".to_string())
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
     }
-    dot_product / (norm_a * norm_b)
 }
@@ -0,0 +1,172 @@
+use super::{
+    DEFAULT_EMBED_BATCH_SIZE, EmbedVendor, Embedding, RagStore, TextChunk, embed_chunks,
+    http_client,
+};
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+// Bumped whenever `IndexFile` changes shape.
+const FORMAT_VERSION: u32 = 1;
+
+// A saved store, so a large context doesn't have to be embedded on every start.
+#[derive(Serialize)]
+struct IndexFile {
+    format_version: u32,
+    embedding_model: String,
+    // whether `embeddings` hold f16 bits (the compressed-embeddings feature)
+    compressed: bool,
+    mtimes: HashMap<String, SystemTime>,
+    chunks: Vec<TextChunk>,
+    embeddings: Vec<Embedding>,
+}
+
+// The leading fields of `IndexFile`, readable whatever type `embeddings` was written with.
+#[derive(Deserialize)]
+struct IndexHeader {
+    format_version: u32,
+    embedding_model: String,
+    compressed: bool,
+}
+
+// Decodes one value from the front of `bytes` and returns it with the rest.
+fn decode_front<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<(T, &[u8])> {
+    let (value, read) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())?;
+    Ok((value, &bytes[read..]))
+}
+
+impl RagStore {
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let index = IndexFile {
+            format_version: FORMAT_VERSION,
+            embedding_model: self.embed_model.clone(),
+            compressed: cfg!(feature = "compressed-embeddings"),
+            mtimes: self.mtimes.clone(),
+            chunks: self.chunks.clone(),
+            embeddings: self.embeddings.clone(),
+        };
+        let bytes = bincode::serde::encode_to_vec(&index, bincode::config::standard())
+            .context("Failed to serialize RAG index")?;
+        fs::write(path, bytes).with_context(|| format!("Failed to write index file: {:?}", path))
+    }
+
+    // Loads an exported store. Chunks embedded with a model other than `embed_model` (or
+    // stored in the other embedding format) are embedded again with `embed_vendor` and
+    // the file is updated.
+    pub async fn import(
+        api_key: String,
+        path: &Path,
+        embed_vendor: EmbedVendor,
+        embed_model: &str,
+    ) -> Result<Self> {
+        let bytes =
+            fs::read(path).with_context(|| format!("Failed to read index file: {:?}", path))?;
+        let parse_error = || format!("Failed to parse index file: {:?}", path);
+        let (header, rest): (IndexHeader, _) = decode_front(&bytes).with_context(parse_error)?;
+        if header.format_version != FORMAT_VERSION {
+            return Err(anyhow!(
+                "Index file {:?} has format version {}, expected {}",
+                path,
+                header.format_version,
+                FORMAT_VERSION
+            ));
+        }
+        let (mtimes, rest): (HashMap<String, SystemTime>, _) =
+            decode_front(rest).with_context(parse_error)?;
+        let (chunks, rest): (Vec<TextChunk>, _) = decode_front(rest).with_context(parse_error)?;
+
+        // embeddings in the other format can't be decoded as `Embedding`, so they are only
+        // read when they will be kept
+        let stale = header.embedding_model != embed_model
+            || header.compressed != cfg!(feature = "compressed-embeddings");
+        let embeddings = if stale {
+            println!(
+                "Index was embedded with '{}', re-embedding {} chunks with '{}'...",
+                header.embedding_model,
+                chunks.len(),
+                embed_model
+            );
+            embed_chunks(
+                &http_client(),
+                embed_vendor,
+                &api_key,
+                embed_model,
+                &chunks,
+                DEFAULT_EMBED_BATCH_SIZE,
+                None,
+            )
+            .await?
+        } else {
+            let (embeddings, _): (Vec<Embedding>, _) =
+                decode_front(rest).with_context(parse_error)?;
+            if chunks.len() != embeddings.len() {
+                return Err(anyhow!(
+                    "Index file {:?} is corrupt: {} chunks but {} embeddings",
+                    path,
+                    chunks.len(),
+                    embeddings.len()
+                ));
+            }
+            println!("Loaded {} chunks from {}", chunks.len(), path.display());
+            embeddings
+        };
+
+        let store = RagStore::builder(api_key, &[])
+            .embed_vendor(embed_vendor)
+            .embed_model(embed_model)
+            .finish(chunks, embeddings, mtimes);
+        if stale {
+            store.export(path)?;
+        }
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct OtherFormatIndex {
+        format_version: u32,
+        embedding_model: String,
+        compressed: bool,
+        mtimes: HashMap<String, SystemTime>,
+        chunks: Vec<TextChunk>,
+        #[cfg(feature = "compressed-embeddings")]
+        embeddings: Vec<Vec<f32>>,
+        #[cfg(not(feature = "compressed-embeddings"))]
+        embeddings: Vec<Vec<u16>>,
+    }
+
+    #[test]
+    fn header_and_chunks_decode_from_the_other_embedding_format() {
+        let chunk = TextChunk {
+            source: "notes.md".to_string(),
+            text: "hello".to_string(),
+            start_line: 1,
+            end_line: 1,
+        };
+        let index = OtherFormatIndex {
+            format_version: FORMAT_VERSION,
+            embedding_model: "m".to_string(),
+            compressed: !cfg!(feature = "compressed-embeddings"),
+            mtimes: HashMap::new(),
+            chunks: vec![chunk],
+            embeddings: vec![vec![Default::default(); 3]],
+        };
+        let bytes = bincode::serde::encode_to_vec(&index, bincode::config::standard()).unwrap();
+
+        let (header, rest): (IndexHeader, _) = decode_front(&bytes).unwrap();
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.embedding_model, "m");
+        assert_ne!(header.compressed, cfg!(feature = "compressed-embeddings"));
+        let (_, rest): (HashMap<String, SystemTime>, _) = decode_front(rest).unwrap();
+        let (chunks, _): (Vec<TextChunk>, _) = decode_front(rest).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "hello");
+    }
+}
@@ -0,0 +1,108 @@
+// Cosine similarity for f32 embeddings. The `simd` feature accumulates 8 lanes at a time,
+// with a hand-written AVX2 path when the build targets it (e.g. -C target-cpu=native).
+// benches/cos_sim.rs includes this file directly, so keep it free of crate imports.
+// `cos_sim_f16` is the compressed-embeddings counterpart.
+
+pub fn cos_sim(a: &[f32], b: &[f32]) -> f32 {
+    let (dot_product, norm_a, norm_b) = dot_and_norms(a, b);
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot_product / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+// Cosine similarity over f16 bit patterns, accumulated in f32 in a single pass.
+#[cfg(feature = "compressed-embeddings")]
+pub fn cos_sim_f16(a: &[u16], b: &[u16]) -> f32 {
+    let (mut dot_product, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+    for (&x, &y) in a.iter().zip(b) {
+        let x = half::f16::from_bits(x).to_f32();
+        let y = half::f16::from_bits(y).to_f32();
+        dot_product += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot_product / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+// (a.b, |a|^2, |b|^2) added onto `acc`, one element at a time
+fn accumulate(a: &[f32], b: &[f32], acc: (f32, f32, f32)) -> (f32, f32, f32) {
+    a.iter().zip(b).fold(acc, |(dot, norm_a, norm_b), (x, y)| {
+        (dot + x * y, norm_a + x * x, norm_b + y * y)
+    })
+}
+
+#[cfg(not(feature = "simd"))]
+fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    accumulate(a, b, (0.0, 0.0, 0.0))
+}
+
+#[cfg(feature = "simd")]
+const LANES: usize = 8;
+
+// Portable version: one pass per sum with 8 independent lane accumulators, which the
+// compiler turns into vector ops. Summing all three in one loop defeats that.
+#[cfg(all(
+    feature = "simd",
+    not(all(target_arch = "x86_64", target_feature = "avx2"))
+))]
+fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    (dot_lanes(a, b), dot_lanes(a, a), dot_lanes(b, b))
+}
+
+#[cfg(all(
+    feature = "simd",
+    not(all(target_arch = "x86_64", target_feature = "avx2"))
+))]
+fn dot_lanes(a: &[f32], b: &[f32]) -> f32 {
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let (a_rest, b_rest) = (a_chunks.remainder(), b_chunks.remainder());
+
+    let mut lanes = [0.0f32; LANES];
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for lane in 0..LANES {
+            lanes[lane] += x[lane] * y[lane];
+        }
+    }
+    lanes.iter().sum::<f32>() + accumulate(a_rest, b_rest, (0.0, 0.0, 0.0)).0
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64", target_feature = "avx2"))]
+fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    use std::arch::x86_64::*;
+
+    let len = a.len().min(b.len());
+    let split = len - len % LANES;
+    let horizontal_sum = |v: __m256| {
+        let mut lanes = [0.0f32; LANES];
+        // SAFETY: `lanes` has room for exactly one __m256
+        unsafe { _mm256_storeu_ps(lanes.as_mut_ptr(), v) };
+        lanes.iter().sum::<f32>()
+    };
+
+    // SAFETY: avx2 is enabled for the whole build (see the cfg above), and every load
+    // reads LANES floats starting below `split`, which is within both slices.
+    let (dot, norm_a, norm_b) = unsafe {
+        let mut dot = _mm256_setzero_ps();
+        let mut norm_a = _mm256_setzero_ps();
+        let mut norm_b = _mm256_setzero_ps();
+        for i in (0..split).step_by(LANES) {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            let y = _mm256_loadu_ps(b.as_ptr().add(i));
+            dot = _mm256_add_ps(dot, _mm256_mul_ps(x, y));
+            norm_a = _mm256_add_ps(norm_a, _mm256_mul_ps(x, x));
+            norm_b = _mm256_add_ps(norm_b, _mm256_mul_ps(y, y));
+        }
+        (dot, norm_a, norm_b)
+    };
+    let sums = (
+        horizontal_sum(dot),
+        horizontal_sum(norm_a),
+        horizontal_sum(norm_b),
+    );
+    accumulate(&a[split..len], &b[split..len], sums)
+}
@@ -0,0 +1,155 @@
+use super::{CHUNK_OVERLAP, MAX_CHUNK_SIZE, TextChunk, chunk_text};
+use anyhow::{Result, anyhow};
+use ego_tree::iter::Edge;
+use scraper::{Html, Node};
+
+const USER_AGENT: &str = concat!("aiterm/", env!("CARGO_PKG_VERSION"));
+
+// Text of elements that never hold readable content
+const SKIPPED_ELEMENTS: &[&str] = &["script", "style", "noscript", "head", "template", "svg"];
+// Elements whose text ends a line, everything else is joined with spaces
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "li",
+    "tr",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "blockquote",
+    "section",
+    "article",
+    "header",
+    "footer",
+    "td",
+    "th",
+    "dt",
+    "dd",
+    "br",
+];
+
+pub(super) fn client() -> reqwest::Client {
+    crate::vendors::http_client_builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .unwrap_or_default()
+}
+
+// Fetches each page and chunks its visible text, with the URL as the chunk source.
+// Pages that fail to load or that robots.txt disallows are skipped with a warning.
+pub(super) async fn load_and_chunk_urls(
+    client: &reqwest::Client,
+    urls: &[String],
+) -> Vec<TextChunk> {
+    let mut chunks = Vec::new();
+    for url in urls {
+        match fetch_text(client, url).await {
+            Ok(Some(text)) => chunks.extend(chunk_text(url, &text, MAX_CHUNK_SIZE, CHUNK_OVERLAP)),
+            Ok(None) => println!("Warning: Skipping {}, disallowed by robots.txt", url),
+            Err(e) => println!("Warning: Skipping {}: {}", url, e),
+        }
+    }
+    chunks
+}
+
+// None when robots.txt disallows the page
+async fn fetch_text(client: &reqwest::Client, url: &str) -> Result<Option<String>> {
+    let url = reqwest::Url::parse(url)?;
+    if !robots_allows(client, &url).await {
+        return Ok(None);
+    }
+    let res = client.get(url).send().await?;
+    if !res.status().is_success() {
+        return Err(anyhow!("HTTP {}", res.status()));
+    }
+    Ok(Some(html_to_text(&res.text().await?)))
+}
+
+// A missing or unreadable robots.txt allows everything. Only the `*` and `aiterm` groups
+// are considered; the longest matching Allow/Disallow rule wins.
+async fn robots_allows(client: &reqwest::Client, url: &reqwest::Url) -> bool {
+    let Ok(robots_url) = url.join("/robots.txt") else {
+        return true;
+    };
+    let robots = match client.get(robots_url).send().await {
+        Ok(res) if res.status().is_success() => res.text().await.unwrap_or_default(),
+        _ => return true,
+    };
+    robots_allows_path(&robots, url.path())
+}
+
+fn robots_allows_path(robots: &str, path: &str) -> bool {
+    let mut in_group = false;
+    let mut previous_was_agent = false;
+    let mut best: Option<(usize, bool)> = None;
+    for line in robots.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+        match key.as_str() {
+            "user-agent" => {
+                // consecutive user-agent lines share one group
+                let matches = value == "*" || value.eq_ignore_ascii_case("aiterm");
+                in_group = if previous_was_agent {
+                    in_group || matches
+                } else {
+                    matches
+                };
+                previous_was_agent = true;
+                continue;
+            }
+            "allow" | "disallow" if in_group && !value.is_empty() && path.starts_with(value) => {
+                let allow = key == "allow";
+                if best.is_none_or(|(len, _)| value.len() > len) {
+                    best = Some((value.len(), allow));
+                }
+            }
+            _ => {}
+        }
+        previous_was_agent = false;
+    }
+    best.is_none_or(|(_, allow)| allow)
+}
+
+fn html_to_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let mut text = String::new();
+    // > 0 while inside an element whose text is skipped
+    let mut skip_depth = 0;
+    for edge in document.tree.root().traverse() {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Element(e) if skip_depth > 0 || SKIPPED_ELEMENTS.contains(&e.name()) => {
+                    skip_depth += 1;
+                }
+                Node::Text(node_text) if skip_depth == 0 => {
+                    let content = node_text.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if content.is_empty() {
+                        continue;
+                    }
+                    if !text.is_empty() && !text.ends_with('\n') {
+                        text.push(' ');
+                    }
+                    text.push_str(&content);
+                }
+                _ => {}
+            },
+            Edge::Close(node) => {
+                if let Node::Element(e) = node.value() {
+                    if skip_depth > 0 {
+                        skip_depth -= 1;
+                    } else if BLOCK_ELEMENTS.contains(&e.name()) && !text.ends_with('\n') {
+                        text.push('\n');
+                    }
+                }
+            }
+        }
+    }
+    text
+}
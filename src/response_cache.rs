@@ -0,0 +1,115 @@
+use crate::config;
+use crate::vendors::{GenerationConfig, Message};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+// `ask --cache` responses, one plain text file per request named by its key.
+
+fn cache_dir() -> Result<PathBuf> {
+    Ok(config::get_config_dir()?.join("response_cache"))
+}
+
+// Covers everything that shapes the answer: persona, the "<vendor>/<version>" that
+// answers and its sampling settings, retrieved context (order independent), system
+// prompt, earlier history and the question itself.
+pub fn key(
+    persona_name: &str,
+    model: &str,
+    config: &GenerationConfig,
+    context_chunks: &[String],
+    system_prompt: &str,
+    history: &[Message],
+    prompt: &str,
+) -> String {
+    let mut chunk_hashes: Vec<String> = context_chunks
+        .iter()
+        .map(|chunk| format!("{:x}", Sha256::digest(chunk.as_bytes())))
+        .collect();
+    chunk_hashes.sort();
+
+    let mut hasher = Sha256::new();
+    // length-prefixed so field boundaries can't shift between keys
+    let mut field = |value: &str| {
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value.as_bytes());
+    };
+    field(persona_name);
+    field(model);
+    field(&format!(
+        "{:?} {:?} {:?} {:?}",
+        config.temperature, config.top_p, config.max_tokens, config.thinking_budget
+    ));
+    for hash in &chunk_hashes {
+        field(hash);
+    }
+    field(system_prompt);
+    for message in history {
+        field(&message.role);
+        field(&message.content);
+    }
+    field(prompt);
+    format!("{:x}", hasher.finalize())
+}
+
+// The cached response, unless it is missing or older than `ttl`.
+pub fn get(key: &str, ttl: Duration) -> Option<String> {
+    let path = cache_dir().ok()?.join(key);
+    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    if age > ttl {
+        return None;
+    }
+    fs::read_to_string(path).ok()
+}
+
+pub fn put(key: &str, response: &str) -> Result<()> {
+    let dir = cache_dir()?;
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create cache dir: {:?}", dir))?;
+    fs::write(dir.join(key), response)
+        .with_context(|| format!("Failed to write cache entry in {:?}", dir))
+}
+
+// Deletes every cached response and returns how many there were.
+pub fn clear() -> Result<usize> {
+    let dir = cache_dir()?;
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let count = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read cache dir: {:?}", dir))?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .count();
+    fs::remove_dir_all(&dir).with_context(|| format!("Failed to remove cache dir: {:?}", dir))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_depends_on_model_and_generation_config() {
+        let config = GenerationConfig::default();
+        let key_for = |model, config: &GenerationConfig| key("p", model, config, &[], "", &[], "q");
+        let gemini = key_for("gemini/gemini-1.5-flash", &config);
+        assert_eq!(gemini, key_for("gemini/gemini-1.5-flash", &config));
+        assert_ne!(gemini, key_for("groq/llama-3.3-70b-versatile", &config));
+
+        let warmer = GenerationConfig {
+            temperature: Some(0.9),
+            ..GenerationConfig::default()
+        };
+        assert_ne!(gemini, key_for("gemini/gemini-1.5-flash", &warmer));
+        let thinking = GenerationConfig {
+            thinking_budget: Some(1024),
+            ..GenerationConfig::default()
+        };
+        assert_ne!(gemini, key_for("gemini/gemini-1.5-flash", &thinking));
+    }
+}
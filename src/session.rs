@@ -0,0 +1,30 @@
+use crate::vendors::Message;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+// A session file is just the JSON-serialized message history. A missing file is an
+// empty session.
+pub fn load(path: &Path) -> Result<Vec<Message>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read session file: {:?}", path))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse session file: {:?}", path))
+}
+
+pub fn save(path: &Path, messages: &[Message]) -> Result<()> {
+    let content = serde_json::to_string_pretty(messages)?;
+    fs::write(path, content).with_context(|| format!("Failed to write session file: {:?}", path))
+}
+
+// Drops the oldest messages so at most `max` remain. The history still starts with a user
+// message, so an odd `max` keeps one fewer.
+pub fn keep_last(messages: &mut Vec<Message>, max: usize) {
+    let excess = messages.len().saturating_sub(max);
+    messages.drain(..excess);
+    let leading_replies = messages.iter().take_while(|m| m.role != "user").count();
+    messages.drain(..leading_replies);
+}
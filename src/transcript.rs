@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Serialize, Debug)]
+pub struct TranscriptTurn {
+    pub agent: String,
+    pub response: String,
+}
+
+// A finished `converse` session, written out as Markdown or JSON.
+#[derive(Serialize, Debug)]
+pub struct Transcript {
+    pub date: String,
+    pub personas: Vec<String>,
+    pub turns: usize,
+    pub initial_prompt: String,
+    pub conversation: Vec<TranscriptTurn>,
+}
+
+impl Transcript {
+    pub fn new(personas: Vec<String>, turns: usize, initial_prompt: String) -> Self {
+        Self {
+            date: chrono::Local::now().to_rfc3339(),
+            personas,
+            turns,
+            initial_prompt,
+            conversation: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, agent: &str, response: &str) {
+        self.conversation.push(TranscriptTurn {
+            agent: agent.to_string(),
+            response: response.to_string(),
+        });
+    }
+
+    // `.json` paths get JSON, anything else gets Markdown with YAML front-matter
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let is_json = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let content = if is_json {
+            serde_json::to_string_pretty(self)?
+        } else {
+            self.to_markdown()?
+        };
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write transcript: {:?}", path))?;
+        Ok(())
+    }
+
+    fn to_markdown(&self) -> Result<String> {
+        // JSON strings and arrays are valid YAML, so they take care of quoting
+        let mut md = String::from("---\n");
+        md.push_str(&format!("date: {}\n", serde_json::to_string(&self.date)?));
+        md.push_str(&format!(
+            "personas: {}\n",
+            serde_json::to_string(&self.personas)?
+        ));
+        md.push_str(&format!("turns: {}\n", self.turns));
+        md.push_str(&format!(
+            "initial_prompt: {}\n",
+            serde_json::to_string(&self.initial_prompt)?
+        ));
+        md.push_str("---\n");
+        for turn in &self.conversation {
+            md.push_str(&format!("\n## {}\n\n{}\n", turn.agent, turn.response));
+        }
+        Ok(md)
+    }
+}
+
+// How `converse` reports turns while it runs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum TranscriptFormat {
+    // nothing at all, only the exit code
+    None,
+    // the decorated console output
+    #[default]
+    Text,
+    // one JSON object per turn
+    Jsonl,
+    Markdown,
+}
+
+// One line of a JSONL transcript. The observer's summary has no turn number.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TurnRecord {
+    pub turn: Option<usize>,
+    pub agent: String,
+    pub timestamp: String,
+    pub response: String,
+}
+
+// The records of a JSONL transcript, for `converse --seed-file`. Blank lines are skipped.
+pub fn read_turn_records(path: &Path) -> Result<Vec<TurnRecord>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read transcript: {:?}", path))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).with_context(|| {
+                format!("Invalid transcript record on line {} of {:?}", i + 1, path)
+            })
+        })
+        .collect()
+}
+
+// A line diff of two JSONL transcripts, e.g. of one scenario run with two versions of a
+// persona. Turns are matched by turn number and agent; `color` marks removed lines red
+// and added ones green.
+pub fn diff_turn_records(old: &[TurnRecord], new: &[TurnRecord], color: bool) -> String {
+    // the observer's summary (no turn number) sorts last
+    let key = |record: &TurnRecord| (record.turn.unwrap_or(usize::MAX), record.agent.clone());
+    let mut turns = BTreeMap::new();
+    for record in old {
+        turns.entry(key(record)).or_insert([None, None])[0] = Some(record.response.as_str());
+    }
+    for record in new {
+        turns.entry(key(record)).or_insert([None, None])[1] = Some(record.response.as_str());
+    }
+    let paint = |code: &str, line: String| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, line)
+        } else {
+            line
+        }
+    };
+
+    let mut out = String::new();
+    for ((turn, agent), responses) in turns {
+        let heading = match turn {
+            usize::MAX => "Summary".to_string(),
+            turn => format!("Turn {}", turn),
+        };
+        out.push_str(&format!("--- {} | {} ---\n", heading, agent));
+        match responses {
+            [Some(old), Some(new)] if old == new => out.push_str("(unchanged)\n"),
+            [Some(old), Some(new)] => {
+                for change in TextDiff::from_lines(old, new).iter_all_changes() {
+                    let line = change.to_string_lossy();
+                    let line = line.trim_end_matches('\n');
+                    out.push_str(&match change.tag() {
+                        ChangeTag::Delete => paint("31", format!("-{}", line)),
+                        ChangeTag::Insert => paint("32", format!("+{}", line)),
+                        ChangeTag::Equal => format!(" {}", line),
+                    });
+                    out.push('\n');
+                }
+            }
+            [Some(_), None] => out.push_str(&paint("31", "(only in the first file)".into())),
+            [None, Some(_)] => out.push_str(&paint("32", "(only in the second file)".into())),
+            [None, None] => {}
+        }
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Writes every turn as soon as it is finished, to a file or stdout. Text written to
+// stdout is the live console output itself, so nothing extra is written for it.
+pub struct TurnLog {
+    format: TranscriptFormat,
+    out: Option<Box<dyn Write>>,
+}
+
+impl TurnLog {
+    pub fn new(format: TranscriptFormat, path: Option<&Path>) -> Result<Self> {
+        let out: Option<Box<dyn Write>> = match (format, path) {
+            (TranscriptFormat::None, _) => None,
+            (_, Some(path)) => {
+                Some(Box::new(fs::File::create(path).with_context(|| {
+                    format!("Failed to create transcript: {:?}", path)
+                })?))
+            }
+            (TranscriptFormat::Text, None) => None,
+            (_, None) => Some(Box::new(io::stdout())),
+        };
+        Ok(Self { format, out })
+    }
+
+    pub fn turn(&mut self, turn: Option<usize>, agent: &str, response: &str) -> Result<()> {
+        let Some(out) = &mut self.out else {
+            return Ok(());
+        };
+        let heading = match turn {
+            Some(turn) => format!("Turn {}", turn),
+            None => "Summary".to_string(),
+        };
+        match self.format {
+            TranscriptFormat::None => {}
+            TranscriptFormat::Text => {
+                writeln!(out, "--- {} | {} ---\n{}\n", heading, agent, response)?
+            }
+            TranscriptFormat::Jsonl => {
+                let record = TurnRecord {
+                    turn,
+                    agent: agent.to_string(),
+                    timestamp: chrono::Local::now().to_rfc3339(),
+                    response: response.to_string(),
+                };
+                writeln!(out, "{}", serde_json::to_string(&record)?)?;
+            }
+            TranscriptFormat::Markdown => {
+                write!(out, "## {} — {}\n\n{}\n\n", heading, agent, response)?
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcript_diff_matches_turns_by_number_and_agent() {
+        let record = |turn, agent: &str, response: &str| TurnRecord {
+            turn,
+            agent: agent.to_string(),
+            timestamp: String::new(),
+            response: response.to_string(),
+        };
+        let old = [
+            record(Some(1), "alice", "hi\nthere"),
+            record(Some(2), "bob", "same"),
+        ];
+        let new = [
+            record(Some(2), "bob", "same"),
+            record(Some(1), "alice", "hi\nyou"),
+            record(None, "observer", "done"),
+        ];
+
+        assert_eq!(
+            diff_turn_records(&old, &new, false),
+            "--- Turn 1 | alice ---\n hi\n-there\n+you\n\n\
+             --- Turn 2 | bob ---\n(unchanged)\n\n\
+             --- Summary | observer ---\n(only in the second file)\n\n"
+        );
+    }
+}
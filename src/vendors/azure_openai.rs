@@ -0,0 +1,68 @@
+use super::openai_compatible::{RequestBody, response_stream};
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_trait::async_trait;
+use tokio_stream::StreamExt;
+
+const API_VERSION: &str = "2024-02-01";
+
+pub struct AzureOpenAI {
+    api_key: String,
+    endpoint: String,
+    deployment: String,
+    client: reqwest::Client,
+}
+
+impl AzureOpenAI {
+    // `endpoint` is the resource URL, e.g. https://my-resource.openai.azure.com
+    pub fn new(api_key: String, endpoint: String, deployment: String) -> Self {
+        Self {
+            api_key,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            deployment,
+            client: super::http_client(),
+        }
+    }
+}
+
+#[async_trait]
+impl LanguageModel for AzureOpenAI {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        let mut stream = self.ask_stream(messages, config).await?;
+        let mut full_response = String::new();
+        while let Some(chunk_result) = stream.next().await {
+            full_response.push_str(&chunk_result?);
+        }
+        Ok(full_response)
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        let url = format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint, self.deployment, API_VERSION
+        );
+        // the deployment in the URL picks the model
+        let request_body = RequestBody::new(None, messages, config);
+
+        let res = self
+            .client
+            .post(&url)
+            .header("api-key", &self.api_key)
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+
+        Ok(response_stream(res))
+    }
+}
@@ -0,0 +1,156 @@
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+// Request Structures (v2 chat)
+#[derive(Serialize)]
+struct RequestBody<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(rename = "p", skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+// Response Structures, one per SSE `data:` line
+#[derive(Deserialize)]
+struct StreamEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    delta: Option<StreamDelta>,
+}
+#[derive(Deserialize)]
+struct StreamDelta {
+    message: Option<StreamDeltaMessage>,
+}
+#[derive(Deserialize)]
+struct StreamDeltaMessage {
+    content: Option<StreamDeltaContent>,
+}
+#[derive(Deserialize)]
+struct StreamDeltaContent {
+    #[serde(default)]
+    text: String,
+}
+
+pub struct Cohere {
+    api_key: String,
+    model: String,
+    client: reqwest::Client,
+}
+
+impl Cohere {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self {
+            api_key,
+            model,
+            client: super::http_client(),
+        }
+    }
+}
+
+#[async_trait]
+impl LanguageModel for Cohere {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        let mut stream = self.ask_stream(messages, config).await?;
+        let mut full_response = String::new();
+        while let Some(chunk_result) = stream.next().await {
+            full_response.push_str(&chunk_result?);
+        }
+        Ok(full_response)
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        if messages.is_empty() {
+            return Err(VendorError::InvalidRequest(
+                "Cohere needs at least one message".into(),
+            ));
+        }
+        let messages = messages
+            .iter()
+            .map(|msg| ChatMessage {
+                role: match msg.role.as_str() {
+                    "user" => "user",
+                    "system" => "system",
+                    _ => "assistant",
+                },
+                content: &msg.content,
+            })
+            .collect();
+
+        let request_body = RequestBody {
+            model: &self.model,
+            messages,
+            stream: true,
+            temperature: config.temperature,
+            top_p: config.top_p,
+            max_tokens: config.max_tokens,
+        };
+
+        let res = self
+            .client
+            .post("https://api.cohere.com/v2/chat")
+            .bearer_auth(&self.api_key)
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+
+        let mut byte_stream = res.bytes_stream();
+
+        let stream = try_stream! {
+            let mut buffer = String::new();
+            while let Some(chunk_result) = byte_stream.next().await {
+                let chunk = chunk_result?;
+                buffer.push_str(&String::from_utf8_lossy(&chunk));
+
+                while let Some(newline_idx) = buffer.find('\n') {
+                    let line: String = buffer.drain(..=newline_idx).collect();
+                    let Some(data) = line.trim().strip_prefix("data:") else { continue };
+                    let Ok(event) = serde_json::from_str::<StreamEvent>(data.trim()) else {
+                        continue;
+                    };
+                    if event.event_type == "message-end" {
+                        return;
+                    }
+                    if event.event_type == "content-delta"
+                        && let Some(text) = event
+                            .delta
+                            .and_then(|delta| delta.message)
+                            .and_then(|message| message.content)
+                            .map(|content| content.text)
+                        && !text.is_empty()
+                    {
+                        yield text;
+                    }
+                }
+            }
+            // the connection closed before the message-end event
+            Err(VendorError::StreamInterrupted)?;
+        };
+
+        Ok(Box::pin(stream))
+    }
+}
@@ -1,13 +1,104 @@
-use super::{LanguageModel, Message, ResponseStream};
+use super::{
+    AskWithToolsResult, GenerationConfig, InlineImage, LanguageModel, Message, ModelInfo,
+    ResponseMetadata, ResponseStream, ToolCall, ToolDefinition, VendorError,
+};
 use async_stream::try_stream;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use tokio_stream::StreamExt;
+use std::collections::HashMap;
+use std::pin::Pin;
+use tokio_stream::{Stream, StreamExt};
+
+// used when a persona has no model_version
+pub const DEFAULT_MODEL: &str = "gemini-1.5-flash";
+// thinkingBudget for `ask --thinking` without --thinking-budget
+pub const DEFAULT_THINKING_BUDGET: u32 = 8192;
+
+const KNOWN_HARM_CATEGORIES: &[&str] = &[
+    "HARM_CATEGORY_HARASSMENT",
+    "HARM_CATEGORY_HATE_SPEECH",
+    "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+    "HARM_CATEGORY_DANGEROUS_CONTENT",
+    "HARM_CATEGORY_CIVIC_INTEGRITY",
+];
+const KNOWN_BLOCK_THRESHOLDS: &[&str] = &[
+    "HARM_BLOCK_THRESHOLD_UNSPECIFIED",
+    "BLOCK_LOW_AND_ABOVE",
+    "BLOCK_MEDIUM_AND_ABOVE",
+    "BLOCK_ONLY_HIGH",
+    "BLOCK_NONE",
+    "OFF",
+];
 
 // Request Structures
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct RequestBody {
     contents: Vec<RequestContent>,
+    generation_config: RequestGenerationConfig,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    safety_settings: Vec<SafetySetting>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<RequestTool>,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestTool {
+    function_declarations: Vec<ToolDefinition>,
+}
+#[derive(Serialize, Clone)]
+struct SafetySetting {
+    category: String,
+    threshold: String,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<usize>,
+    // "application/json" when a response schema is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<ThinkingConfig>,
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThinkingConfig {
+    thinking_budget: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_thoughts: Option<bool>,
+}
+
+impl RequestGenerationConfig {
+    fn new(config: &GenerationConfig, candidate_count: Option<usize>) -> Self {
+        Self {
+            temperature: config.temperature,
+            top_p: config.top_p,
+            max_output_tokens: config.max_tokens,
+            candidate_count,
+            response_mime_type: config.response_schema.as_ref().map(|_| "application/json"),
+            response_schema: config.response_schema.clone(),
+            thinking_config: config
+                .thinking_budget
+                .map(|thinking_budget| ThinkingConfig {
+                    thinking_budget,
+                    include_thoughts: config.include_thoughts.then_some(true),
+                }),
+        }
+    }
+}
+#[derive(Serialize)]
+struct CountTokensRequest {
+    contents: Vec<RequestContent>,
 }
 #[derive(Serialize)]
 struct RequestContent {
@@ -15,89 +106,227 @@ struct RequestContent {
     parts: Vec<RequestPart>,
 }
 #[derive(Serialize)]
-struct RequestPart {
-    text: String,
+#[serde(untagged)]
+enum RequestPart {
+    Text {
+        text: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    InlineData {
+        inline_data: RequestInlineData,
+    },
+}
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestInlineData {
+    mime_type: String,
+    data: String,
 }
 
 // Response Structures
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CountTokensResponse {
+    total_tokens: u32,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListModelsResponse {
+    #[serde(default)]
+    models: Vec<ApiModel>,
+    next_page_token: Option<String>,
+}
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiModel {
+    name: String,
+    #[serde(default)]
+    display_name: String,
+    input_token_limit: Option<u32>,
+    output_token_limit: Option<u32>,
+}
+// Gemini 2.0 and later can end a stream with a chunk holding only usage metadata, or a
+// candidate with a finish reason but no content, and may split a reply (or return
+// "thought" summaries) across several parts. Hence all the defaults.
+#[derive(Deserialize)]
 struct ResponseBody {
+    #[serde(default)]
     candidates: Vec<ResponseCandidate>,
 }
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct ResponseCandidate {
+    #[serde(default)]
     content: ResponseContent,
+    // only on the last streamed chunk
+    finish_reason: Option<String>,
+    #[serde(default)]
+    safety_ratings: Vec<SafetyRating>,
 }
 #[derive(Deserialize)]
+struct SafetyRating {
+    category: String,
+    probability: String,
+}
+#[derive(Deserialize, Default)]
 struct ResponseContent {
+    #[serde(default)]
     parts: Vec<ResponsePart>,
 }
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct ResponsePart {
+    #[serde(default)]
     text: String,
+    #[serde(default)]
+    thought: bool,
+    function_call: Option<FunctionCall>,
+}
+
+impl ResponseContent {
+    // the reply text, without any thought summaries
+    fn text(&self) -> String {
+        self.parts
+            .iter()
+            .filter(|part| !part.thought)
+            .map(|part| part.text.as_str())
+            .collect()
+    }
+
+    // only the thought summaries
+    fn thoughts(&self) -> String {
+        self.parts
+            .iter()
+            .filter(|part| part.thought)
+            .map(|part| part.text.as_str())
+            .collect()
+    }
+}
+#[derive(Deserialize)]
+struct FunctionCall {
+    name: String,
+    #[serde(default)]
+    args: serde_json::Value,
 }
 
+// The response objects of a streamGenerateContent request, as they arrive.
+type BodyStream = Pin<Box<dyn Stream<Item = Result<ResponseBody, VendorError>> + Send>>;
+
 pub struct Gemini {
     api_key: String,
+    // e.g. "gemini-1.5-flash", "gemini-2.0-flash" or "gemini-exp-1206"
+    model_id: String,
     client: reqwest::Client,
+    safety_settings: Vec<SafetySetting>,
+    verbose: bool,
 }
 
 impl Gemini {
-    pub fn new(api_key: String) -> Self {
+    pub fn new(api_key: String, model_id: String) -> Self {
         Self {
             api_key,
-            client: reqwest::Client::new(),
+            model_id,
+            client: super::http_client(),
+            safety_settings: Vec::new(),
+            verbose: false,
         }
     }
-}
 
-#[async_trait]
-impl LanguageModel for Gemini {
-    async fn ask(
-        &self,
-        messages: &[Message],
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let mut stream = self.ask_stream(messages).await?;
-        let mut full_response = String::new();
-        while let Some(chunk_result) = stream.next().await {
-            let chunk = match chunk_result {
-                Ok(c) => c,
-                Err(e) => return Err(e),
-            };
-            full_response.push_str(&chunk);
-        }
-        Ok(full_response)
+    fn url(&self, method: &str) -> String {
+        format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:{}?key={}",
+            self.model_id, method, self.api_key
+        )
     }
 
-    async fn ask_stream(
-        &self,
-        messages: &[Message],
-    ) -> Result<ResponseStream, Box<dyn std::error::Error + Send + Sync>> {
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash:streamGenerateContent?key={}",
-            &self.api_key
-        );
+    // print request bodies and response status/headers to stderr, for debugging
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
 
-        let request_contents: Vec<RequestContent> = messages
+    fn log_response(&self, res: &reqwest::Response) {
+        if self.verbose {
+            eprintln!("[Gemini response] {}", res.status());
+            for (name, value) in res.headers() {
+                eprintln!("  {}: {}", name, value.to_str().unwrap_or("<binary>"));
+            }
+        }
+    }
+
+    // category -> threshold, e.g. HARM_CATEGORY_HATE_SPEECH = "BLOCK_NONE".
+    // Unknown names are sent anyway (the API may know newer ones) but warned about.
+    pub fn with_safety_settings(mut self, settings: &HashMap<String, String>) -> Self {
+        let mut safety_settings: Vec<SafetySetting> = settings
             .iter()
-            .map(|msg| RequestContent {
-                role: msg.role.clone(),
-                parts: vec![RequestPart {
-                    text: msg.content.clone(),
-                }],
+            .map(|(category, threshold)| {
+                if !KNOWN_HARM_CATEGORIES.contains(&category.as_str()) {
+                    eprintln!("Warning: Unknown Gemini safety category '{}'", category);
+                }
+                if !KNOWN_BLOCK_THRESHOLDS.contains(&threshold.as_str()) {
+                    eprintln!(
+                        "Warning: Unknown Gemini safety threshold '{}' for {}",
+                        threshold, category
+                    );
+                }
+                SafetySetting {
+                    category: category.clone(),
+                    threshold: threshold.clone(),
+                }
             })
             .collect();
+        safety_settings.sort_by(|a, b| a.category.cmp(&b.category));
+        self.safety_settings = safety_settings;
+        self
+    }
+
+    // Exact token count from the API's countTokens endpoint.
+    pub async fn count_tokens(&self, text: &str) -> Result<u32, VendorError> {
+        let url = self.url("countTokens");
+        let request_body = CountTokensRequest {
+            contents: vec![RequestContent {
+                role: "user".to_string(),
+                parts: vec![RequestPart::Text {
+                    text: text.to_string(),
+                }],
+            }],
+        };
+
+        let res = self.client.post(&url).json(&request_body).send().await?;
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+        let body: CountTokensResponse = res.json().await?;
+        Ok(body.total_tokens)
+    }
+
+    // The response objects of a streamGenerateContent request, as they arrive.
+    async fn stream_bodies(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<BodyStream, VendorError> {
+        let url = self.url("streamGenerateContent");
+
+        let request_contents = request_contents(messages, &config.images);
 
         let request_body = RequestBody {
             contents: request_contents,
+            generation_config: RequestGenerationConfig::new(config, None),
+            safety_settings: self.safety_settings.clone(),
+            tools: Vec::new(),
         };
 
+        if self.verbose {
+            eprintln!(
+                "[Gemini request]\n{}",
+                serde_json::to_string_pretty(&request_body)?
+            );
+        }
         let res = self.client.post(&url).json(&request_body).send().await?;
+        self.log_response(&res);
 
         if !res.status().is_success() {
-            let status = res.status();
-            let error_text = res.text().await?;
-            return Err(format!("API Error: {} - {}", status, error_text).into());
+            return Err(VendorError::from_response(res).await);
         }
 
         let mut byte_stream = res.bytes_stream();
@@ -125,17 +354,265 @@ impl LanguageModel for Gemini {
                         if let Some(end_idx) = end_idx_opt {
                             let object_str = &buffer[start_idx..end_idx];
                             if let Ok(rb) = serde_json::from_str::<ResponseBody>(object_str) {
-                                if let Some(text) = rb.candidates.first().and_then(|c| c.content.parts.first()).map(|p| p.text.clone()) {
-                                    if !text.is_empty() { yield text; }
-                                }
+                                yield rb;
                             }
                             buffer.drain(..end_idx);
                         } else { break; }
                     } else { buffer.clear(); break; }
                 }
             }
+            // a response object was cut off
+            if buffer.contains('{') {
+                Err(VendorError::StreamInterrupted)?;
+            }
         };
 
         Ok(Box::pin(stream))
     }
 }
+
+// Gemini calls the assistant "model" and rejects two turns in a row from the same
+// role, so roles are mapped and consecutive same-role messages merged into one turn.
+// `images` go after the text of the last user turn.
+fn request_contents(messages: &[Message], images: &[InlineImage]) -> Vec<RequestContent> {
+    let mut contents: Vec<RequestContent> = Vec::new();
+    for msg in messages {
+        let role = match msg.role.as_str() {
+            "assistant" | "model" => "model",
+            _ => "user",
+        };
+        match contents.last_mut() {
+            Some(last) if last.role == role => last.parts.push(RequestPart::Text {
+                text: msg.content.clone(),
+            }),
+            _ => contents.push(RequestContent {
+                role: role.to_string(),
+                parts: vec![RequestPart::Text {
+                    text: msg.content.clone(),
+                }],
+            }),
+        }
+    }
+    if let Some(last_user) = contents.iter_mut().rev().find(|c| c.role == "user") {
+        last_user
+            .parts
+            .extend(images.iter().map(|image| RequestPart::InlineData {
+                inline_data: RequestInlineData {
+                    mime_type: image.mime_type.clone(),
+                    data: image.data.clone(),
+                },
+            }));
+    }
+    contents
+}
+
+#[async_trait]
+impl LanguageModel for Gemini {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        Ok(self.ask_with_metadata(messages, config).await?.0)
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        let bodies = self.stream_bodies(messages, config).await?;
+        Ok(Box::pin(bodies.filter_map(|body| {
+            match body {
+                Ok(body) => body
+                    .candidates
+                    .first()
+                    .map(|c| c.content.text())
+                    .filter(|text| !text.is_empty())
+                    .map(Ok),
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
+
+    // the finish reason and ratings come with the last streamed chunk
+    async fn ask_with_metadata(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<(String, ResponseMetadata), VendorError> {
+        let mut bodies = self.stream_bodies(messages, config).await?;
+        let mut text = String::new();
+        let mut metadata = ResponseMetadata::default();
+        while let Some(body) = bodies.next().await {
+            let Some(candidate) = body?.candidates.into_iter().next() else {
+                continue;
+            };
+            text.push_str(&candidate.content.text());
+            metadata.thoughts.push_str(&candidate.content.thoughts());
+            if candidate.finish_reason.is_some() {
+                metadata.finish_reason = candidate.finish_reason;
+            }
+            if !candidate.safety_ratings.is_empty() {
+                metadata.safety_ratings = candidate
+                    .safety_ratings
+                    .into_iter()
+                    .map(|rating| (rating.category, rating.probability))
+                    .collect();
+            }
+        }
+        Ok((text, metadata))
+    }
+
+    // one request with candidateCount, Gemini allows up to 8
+    async fn ask_candidates(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+        n: usize,
+    ) -> Result<Vec<String>, VendorError> {
+        let url = self.url("generateContent");
+        let request_body = RequestBody {
+            contents: request_contents(messages, &config.images),
+            generation_config: RequestGenerationConfig::new(config, Some(n)),
+            safety_settings: self.safety_settings.clone(),
+            tools: Vec::new(),
+        };
+        if self.verbose {
+            eprintln!(
+                "[Gemini request]\n{}",
+                serde_json::to_string_pretty(&request_body)?
+            );
+        }
+
+        let res = self.client.post(&url).json(&request_body).send().await?;
+        self.log_response(&res);
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+        let body: ResponseBody = res.json().await?;
+        Ok(body
+            .candidates
+            .into_iter()
+            .map(|c| c.content.text())
+            .collect())
+    }
+
+    async fn ask_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Result<AskWithToolsResult, VendorError> {
+        let url = self.url("generateContent");
+        let request_body = RequestBody {
+            contents: request_contents(messages, &[]),
+            generation_config: RequestGenerationConfig::new(&GenerationConfig::default(), None),
+            safety_settings: self.safety_settings.clone(),
+            tools: vec![RequestTool {
+                function_declarations: tools.to_vec(),
+            }],
+        };
+        if self.verbose {
+            eprintln!(
+                "[Gemini request]\n{}",
+                serde_json::to_string_pretty(&request_body)?
+            );
+        }
+
+        let res = self.client.post(&url).json(&request_body).send().await?;
+        self.log_response(&res);
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+        let body: ResponseBody = res.json().await?;
+        let parts = body
+            .candidates
+            .into_iter()
+            .next()
+            .map(|c| c.content.parts)
+            .unwrap_or_default();
+
+        // a call takes precedence over any text the model wrote alongside it
+        let mut text = String::new();
+        for part in parts {
+            if let Some(call) = part.function_call {
+                return Ok(AskWithToolsResult::ToolCall(ToolCall {
+                    name: call.name,
+                    arguments: call.args,
+                }));
+            }
+            if !part.thought {
+                text.push_str(&part.text);
+            }
+        }
+        Ok(AskWithToolsResult::Text(text))
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, VendorError> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = format!(
+                "https://generativelanguage.googleapis.com/v1beta/models?key={}&pageSize=1000",
+                &self.api_key
+            );
+            if let Some(token) = &page_token {
+                url.push_str(&format!("&pageToken={}", token));
+            }
+
+            let res = self.client.get(&url).send().await?;
+            if !res.status().is_success() {
+                return Err(VendorError::from_response(res).await);
+            }
+            let body: ListModelsResponse = res.json().await?;
+            models.extend(body.models.into_iter().map(|m| {
+                ModelInfo {
+                    // names come back as "models/<id>"
+                    id: m
+                        .name
+                        .strip_prefix("models/")
+                        .unwrap_or(&m.name)
+                        .to_string(),
+                    display_name: m.display_name,
+                    input_token_limit: m.input_token_limit,
+                    output_token_limit: m.output_token_limit,
+                }
+            }));
+
+            match body.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break,
+            }
+        }
+        Ok(models)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    // chat and session histories store replies as "assistant"
+    #[test]
+    fn history_roles_become_user_and_model() {
+        let contents = request_contents(
+            &[
+                message("user", "hi"),
+                message("assistant", "hello"),
+                message("user", "again"),
+                message("user", "and again"),
+            ],
+            &[],
+        );
+        let roles: Vec<&str> = contents.iter().map(|c| c.role.as_str()).collect();
+        assert_eq!(roles, ["user", "model", "user"]);
+        assert_eq!(contents[2].parts.len(), 2);
+    }
+}
@@ -0,0 +1,34 @@
+use super::openai_compatible::OpenAICompatible;
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_trait::async_trait;
+
+pub struct Groq(OpenAICompatible);
+
+impl Groq {
+    pub fn new(api_key: String, model: String) -> Self {
+        Self(OpenAICompatible::new(
+            api_key,
+            model,
+            "https://api.groq.com/openai/v1/chat/completions",
+        ))
+    }
+}
+
+#[async_trait]
+impl LanguageModel for Groq {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        self.0.ask(messages, config).await
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        self.0.ask_stream(messages, config).await
+    }
+}
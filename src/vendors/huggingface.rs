@@ -0,0 +1,169 @@
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+const DEFAULT_MAX_NEW_TOKENS: u32 = 512;
+
+// Request Structures (text-generation task)
+#[derive(Serialize)]
+struct RequestBody {
+    inputs: String,
+    parameters: RequestParameters,
+    stream: bool,
+}
+#[derive(Serialize)]
+struct RequestParameters {
+    max_new_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    // only the continuation, not the prompt followed by it
+    return_full_text: bool,
+}
+
+// Response Structures
+#[derive(Deserialize)]
+struct Generation {
+    generated_text: String,
+}
+// one per SSE `data:` line, the last one carries the whole `generated_text`
+#[derive(Deserialize)]
+struct StreamEvent {
+    token: Option<StreamToken>,
+    generated_text: Option<String>,
+    error: Option<String>,
+}
+#[derive(Deserialize)]
+struct StreamToken {
+    text: String,
+    #[serde(default)]
+    special: bool,
+}
+
+pub struct HuggingFaceInference {
+    api_key: String,
+    model_id: String,
+    client: reqwest::Client,
+}
+
+impl HuggingFaceInference {
+    // `model_id` as on the hub, e.g. "mistralai/Mistral-7B-Instruct-v0.3"
+    pub fn new(api_key: String, model_id: String) -> Self {
+        Self {
+            api_key,
+            model_id,
+            client: super::http_client(),
+        }
+    }
+
+    async fn post(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+        stream: bool,
+    ) -> Result<reqwest::Response, VendorError> {
+        let url = format!(
+            "https://api-inference.huggingface.co/models/{}",
+            self.model_id
+        );
+        let request_body = RequestBody {
+            inputs: prompt(messages),
+            parameters: RequestParameters {
+                max_new_tokens: config.max_tokens.unwrap_or(DEFAULT_MAX_NEW_TOKENS),
+                temperature: config.temperature,
+                top_p: config.top_p,
+                return_full_text: false,
+            },
+            stream,
+        };
+
+        // without this, a cold model answers 503 until it has been loaded
+        let res = self
+            .client
+            .post(&url)
+            .bearer_auth(&self.api_key)
+            .header("x-wait-for-model", "true")
+            .json(&request_body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+        Ok(res)
+    }
+}
+
+// The text-generation task takes a single string, so the messages are written out as a
+// transcript ending where the assistant's reply should go.
+fn prompt(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+    for msg in messages {
+        let role = match msg.role.as_str() {
+            "assistant" | "model" => "Assistant",
+            "system" => "System",
+            _ => "User",
+        };
+        prompt.push_str(&format!("{}: {}\n\n", role, msg.content));
+    }
+    prompt.push_str("Assistant:");
+    prompt
+}
+
+#[async_trait]
+impl LanguageModel for HuggingFaceInference {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        let res = self.post(messages, config, false).await?;
+        let generations: Vec<Generation> = res.json().await?;
+        Ok(generations
+            .into_iter()
+            .next()
+            .map(|g| g.generated_text.trim_start().to_string())
+            .unwrap_or_default())
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        let res = self.post(messages, config, true).await?;
+        let mut byte_stream = res.bytes_stream();
+
+        let stream = try_stream! {
+            let mut buffer = String::new();
+            while let Some(chunk_result) = byte_stream.next().await {
+                let chunk = chunk_result?;
+                buffer.push_str(&String::from_utf8_lossy(&chunk));
+
+                while let Some(newline_idx) = buffer.find('\n') {
+                    let line: String = buffer.drain(..=newline_idx).collect();
+                    let Some(data) = line.trim().strip_prefix("data:") else { continue };
+                    let Ok(event) = serde_json::from_str::<StreamEvent>(data.trim()) else { continue };
+                    if let Some(message) = event.error {
+                        Err(VendorError::InvalidRequest(message))?;
+                    }
+                    if let Some(token) = event.token
+                        && !token.special
+                        && !token.text.is_empty()
+                    {
+                        yield token.text;
+                    }
+                    if event.generated_text.is_some() {
+                        return;
+                    }
+                }
+            }
+            // the connection closed before the final event
+            Err(VendorError::StreamInterrupted)?;
+        };
+
+        Ok(Box::pin(stream))
+    }
+}
@@ -0,0 +1,77 @@
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+// Test double that replays canned responses in order, one single-chunk stream each, and
+// records the messages of every request. Running out of responses panics so a test
+// can't silently make extra calls.
+pub struct MockModel {
+    responses: Mutex<VecDeque<String>>,
+    requests: Mutex<Vec<Vec<Message>>>,
+    latency: Option<Duration>,
+}
+
+impl MockModel {
+    pub fn new<S: Into<String>>(responses: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            responses: Mutex::new(responses.into_iter().map(Into::into).collect()),
+            requests: Mutex::new(Vec::new()),
+            latency: None,
+        }
+    }
+
+    // sleep this long before every response, simulating network delay
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    // what each call so far was sent, oldest first
+    pub fn requests(&self) -> Vec<Vec<Message>> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn next_response(&self) -> String {
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("MockModel called more times than it has canned responses")
+    }
+}
+
+#[async_trait]
+impl LanguageModel for MockModel {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        let mut stream = self.ask_stream(messages, config).await?;
+        let mut full_response = String::new();
+        while let Some(chunk) = stream.next().await {
+            full_response.push_str(&chunk?);
+        }
+        Ok(full_response)
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        _config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        self.requests.lock().unwrap().push(messages.to_vec());
+        let response = self.next_response();
+        let latency = self.latency;
+        let stream = async_stream::stream! {
+            if let Some(latency) = latency {
+                tokio::time::sleep(latency).await;
+            }
+            yield Ok(response);
+        };
+        Ok(Box::pin(stream))
+    }
+}
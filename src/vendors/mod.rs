@@ -1,28 +1,311 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::pin::Pin;
+use std::sync::OnceLock;
 use tokio_stream::Stream;
 
+pub mod azure_openai;
+pub mod cohere;
 pub mod gemini;
+pub mod groq;
+pub mod huggingface;
+#[cfg(test)]
+pub mod mock;
+pub mod openai_compatible;
+pub mod together;
 
-pub type StreamChunk = Result<String, Box<dyn std::error::Error + Send + Sync>>;
+// Values accepted for a persona's `model`.
+pub const KNOWN_VENDORS: &[&str] = &[
+    "gemini",
+    "groq",
+    "cohere",
+    "azure-openai",
+    "huggingface",
+    "together",
+];
+
+// Why a vendor request failed.
+#[derive(Debug)]
+pub enum VendorError {
+    // the API answered with a non-success status
+    ApiError { status: u16, message: String },
+    NetworkError(reqwest::Error),
+    ParseError(serde_json::Error),
+    // the response stream ended before the vendor marked it complete
+    StreamInterrupted,
+    // the request can't be expressed for this vendor
+    InvalidRequest(String),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for VendorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VendorError::ApiError { status, message } => {
+                write!(f, "API Error: {} - {}", status, message)
+            }
+            VendorError::NetworkError(e) => write!(f, "{}", e),
+            VendorError::ParseError(e) => write!(f, "Failed to parse response: {}", e),
+            VendorError::StreamInterrupted => write!(f, "Response stream ended unexpectedly"),
+            VendorError::InvalidRequest(message) => write!(f, "{}", message),
+            VendorError::Unsupported(what) => write!(f, "{} is not supported by this vendor", what),
+        }
+    }
+}
+
+// Display already includes the wrapped error's message, so the chain continues below it.
+impl std::error::Error for VendorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VendorError::NetworkError(e) => e.source(),
+            VendorError::ParseError(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for VendorError {
+    fn from(e: reqwest::Error) -> Self {
+        VendorError::NetworkError(e)
+    }
+}
+
+impl From<serde_json::Error> for VendorError {
+    fn from(e: serde_json::Error) -> Self {
+        VendorError::ParseError(e)
+    }
+}
+
+impl VendorError {
+    // reads the error body of a failed response
+    pub async fn from_response(res: reqwest::Response) -> Self {
+        let status = res.status().as_u16();
+        match res.text().await {
+            Ok(message) => VendorError::ApiError { status, message },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+pub type StreamChunk = Result<String, VendorError>;
 pub type ResponseStream = Pin<Box<dyn Stream<Item = StreamChunk> + Send>>;
 
-#[derive(Serialize, Deserialize)]
+// Base for every HTTP client, so requests go through the proxy from config.toml.
+// Without one, reqwest falls back to HTTP(S)_PROXY/NO_PROXY from the environment.
+pub fn http_client_builder() -> reqwest::ClientBuilder {
+    static PROXY: OnceLock<Option<reqwest::Proxy>> = OnceLock::new();
+    let proxy = PROXY.get_or_init(|| {
+        let proxy_url = match crate::config::load_global_config() {
+            Ok(config) => config.proxy_url?,
+            Err(e) => {
+                eprintln!("Warning: {:#}, ignoring it", e);
+                return None;
+            }
+        };
+        reqwest::Proxy::all(&proxy_url)
+            .inspect_err(|e| eprintln!("Warning: Invalid proxy_url '{}': {}", proxy_url, e))
+            .ok()
+    });
+    let builder = reqwest::Client::builder();
+    match proxy {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    }
+}
+
+pub fn http_client() -> reqwest::Client {
+    http_client_builder().build().unwrap_or_default()
+}
+
+// Rough token estimate (~4 chars per token), good enough for budgeting and stats.
+pub fn count_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+// USD per million tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelPricing {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+impl ModelPricing {
+    const fn new(input_per_million: f64, output_per_million: f64) -> Self {
+        Self {
+            input_per_million,
+            output_per_million,
+        }
+    }
+
+    pub fn cost(&self, input_tokens: usize, output_tokens: usize) -> f64 {
+        (input_tokens as f64 * self.input_per_million
+            + output_tokens as f64 * self.output_per_million)
+            / 1_000_000.0
+    }
+}
+
+// List prices by model version, only used for rough estimates so they may lag behind.
+pub const MODEL_PRICING: &[(&str, ModelPricing)] = &[
+    ("gemini-1.5-flash", ModelPricing::new(0.075, 0.30)),
+    ("gemini-1.5-pro", ModelPricing::new(1.25, 5.00)),
+    ("gemini-2.0-flash", ModelPricing::new(0.10, 0.40)),
+    ("llama-3.1-8b-instant", ModelPricing::new(0.05, 0.08)),
+    ("llama-3.1-70b-versatile", ModelPricing::new(0.59, 0.79)),
+    ("llama-3.3-70b-versatile", ModelPricing::new(0.59, 0.79)),
+    ("mixtral-8x7b-32768", ModelPricing::new(0.24, 0.24)),
+    ("command-r", ModelPricing::new(0.15, 0.60)),
+    ("command-r-plus", ModelPricing::new(2.50, 10.00)),
+];
+
+pub fn pricing(model_version: &str) -> Option<ModelPricing> {
+    MODEL_PRICING
+        .iter()
+        .find(|(version, _)| *version == model_version)
+        .map(|(_, pricing)| *pricing)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Message {
     pub role: String,
     pub content: String,
 }
 
+// Sampling parameters, unset fields are left to the vendor's defaults.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationConfig {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    // constrain the response to JSON following this schema, only Gemini supports it
+    pub response_schema: Option<serde_json::Value>,
+    // sent along with the last user message, only Gemini supports them
+    pub images: Vec<InlineImage>,
+    // tokens the model may spend reasoning before it answers, only Gemini supports it
+    pub thinking_budget: Option<u32>,
+    // ask for summaries of that reasoning as well, see ResponseMetadata::thoughts
+    pub include_thoughts: bool,
+}
+
+// An image sent inline with a prompt.
+#[derive(Debug, Clone)]
+pub struct InlineImage {
+    // e.g. "image/png"
+    pub mime_type: String,
+    // base64 of the file
+    pub data: String,
+}
+
+// A function the model may ask to call, `parameters` is a JSON schema of its arguments.
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+// Either a final answer or a request to run one of the tools and report back.
+#[derive(Debug)]
+pub enum AskWithToolsResult {
+    Text(String),
+    ToolCall(ToolCall),
+}
+
+// A model offered by a vendor, token limits are None when the vendor doesn't report them.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    pub id: String,
+    pub display_name: String,
+    pub input_token_limit: Option<u32>,
+    pub output_token_limit: Option<u32>,
+}
+
+// How a response ended, as far as the vendor reports it.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseMetadata {
+    // e.g. "STOP", "MAX_TOKENS" or "SAFETY"
+    pub finish_reason: Option<String>,
+    // harm category and probability, e.g. ("HARM_CATEGORY_HATE_SPEECH", "NEGLIGIBLE")
+    pub safety_ratings: Vec<(String, String)>,
+    // the model's thought summaries, empty unless GenerationConfig::include_thoughts
+    pub thoughts: String,
+}
+
+impl fmt::Display for ResponseMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Finish reason: {}",
+            self.finish_reason.as_deref().unwrap_or("not reported")
+        )?;
+        if !self.safety_ratings.is_empty() {
+            let ratings: Vec<String> = self
+                .safety_ratings
+                .iter()
+                .map(|(category, probability)| format!("{}: {}", category, probability))
+                .collect();
+            write!(f, " | Safety: {}", ratings.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[async_trait]
 pub trait LanguageModel: Send + Sync {
     async fn ask(
         &self,
         messages: &[Message],
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>>;
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError>;
 
     async fn ask_stream(
         &self,
         messages: &[Message],
-    ) -> Result<ResponseStream, Box<dyn std::error::Error + Send + Sync>>;
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError>;
+
+    // `n` independent answers to the same request. Vendors without a native option for
+    // this make `n` separate requests.
+    async fn ask_candidates(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+        n: usize,
+    ) -> Result<Vec<String>, VendorError> {
+        let mut responses = Vec::with_capacity(n);
+        for _ in 0..n {
+            responses.push(self.ask(messages, config).await?);
+        }
+        Ok(responses)
+    }
+
+    // Like `ask`, with the finish reason and safety ratings for vendors that report them.
+    async fn ask_with_metadata(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<(String, ResponseMetadata), VendorError> {
+        Ok((
+            self.ask(messages, config).await?,
+            ResponseMetadata::default(),
+        ))
+    }
+
+    async fn ask_with_tools(
+        &self,
+        _messages: &[Message],
+        _tools: &[ToolDefinition],
+    ) -> Result<AskWithToolsResult, VendorError> {
+        Err(VendorError::Unsupported("Tool calling"))
+    }
+
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, VendorError> {
+        Err(VendorError::Unsupported("Listing models"))
+    }
 }
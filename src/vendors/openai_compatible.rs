@@ -0,0 +1,142 @@
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+// Request Structures (OpenAI-compatible)
+#[derive(Serialize)]
+pub(super) struct RequestBody<'a> {
+    // left out when the URL picks the model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    messages: &'a [Message],
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+impl<'a> RequestBody<'a> {
+    pub(super) fn new(
+        model: Option<&'a str>,
+        messages: &'a [Message],
+        config: &GenerationConfig,
+    ) -> Self {
+        Self {
+            model,
+            messages,
+            stream: true,
+            temperature: config.temperature,
+            top_p: config.top_p,
+            max_tokens: config.max_tokens,
+        }
+    }
+}
+
+// Response Structures, one per SSE `data:` line
+#[derive(Deserialize)]
+struct StreamChunkBody {
+    // empty in the content filter results Azure sends first
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+}
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+}
+#[derive(Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
+
+// The text deltas of a successful streamed chat completions reply.
+pub(super) fn response_stream(res: reqwest::Response) -> ResponseStream {
+    let mut byte_stream = res.bytes_stream();
+
+    let stream = try_stream! {
+        let mut buffer = String::new();
+        while let Some(chunk_result) = byte_stream.next().await {
+            let chunk = chunk_result?;
+            buffer.push_str(&String::from_utf8_lossy(&chunk));
+
+            while let Some(newline_idx) = buffer.find('\n') {
+                let line: String = buffer.drain(..=newline_idx).collect();
+                let Some(data) = line.trim().strip_prefix("data:") else { continue };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    return;
+                }
+                if let Ok(body) = serde_json::from_str::<StreamChunkBody>(data)
+                    && let Some(text) = body.choices.into_iter().next().and_then(|c| c.delta.content)
+                    && !text.is_empty()
+                {
+                    yield text;
+                }
+            }
+        }
+        // the connection closed before the [DONE] marker
+        Err(VendorError::StreamInterrupted)?;
+    };
+
+    Box::pin(stream)
+}
+
+// A vendor serving OpenAI's chat completions API at its own URL, with a bearer token.
+pub struct OpenAICompatible {
+    api_key: String,
+    model: String,
+    url: &'static str,
+    client: reqwest::Client,
+}
+
+impl OpenAICompatible {
+    pub fn new(api_key: String, model: String, url: &'static str) -> Self {
+        Self {
+            api_key,
+            model,
+            url,
+            client: super::http_client(),
+        }
+    }
+}
+
+#[async_trait]
+impl LanguageModel for OpenAICompatible {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        let mut stream = self.ask_stream(messages, config).await?;
+        let mut full_response = String::new();
+        while let Some(chunk_result) = stream.next().await {
+            full_response.push_str(&chunk_result?);
+        }
+        Ok(full_response)
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        let request_body = RequestBody::new(Some(&self.model), messages, config);
+
+        let res = self
+            .client
+            .post(self.url)
+            .bearer_auth(&self.api_key)
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(VendorError::from_response(res).await);
+        }
+        Ok(response_stream(res))
+    }
+}
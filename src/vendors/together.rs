@@ -0,0 +1,35 @@
+use super::openai_compatible::OpenAICompatible;
+use super::{GenerationConfig, LanguageModel, Message, ResponseStream, VendorError};
+use async_trait::async_trait;
+
+pub struct TogetherAI(OpenAICompatible);
+
+impl TogetherAI {
+    // `model` as Together names it, e.g. "meta-llama/Llama-3.3-70B-Instruct-Turbo"
+    pub fn new(api_key: String, model: String) -> Self {
+        Self(OpenAICompatible::new(
+            api_key,
+            model,
+            "https://api.together.xyz/v1/chat/completions",
+        ))
+    }
+}
+
+#[async_trait]
+impl LanguageModel for TogetherAI {
+    async fn ask(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<String, VendorError> {
+        self.0.ask(messages, config).await
+    }
+
+    async fn ask_stream(
+        &self,
+        messages: &[Message],
+        config: &GenerationConfig,
+    ) -> Result<ResponseStream, VendorError> {
+        self.0.ask_stream(messages, config).await
+    }
+}